@@ -1,10 +1,22 @@
-use crate::comglue::{
-    interface::IID_IDISPATCH,
-    variant::{str_to_wstr, Variant},
+use crate::{
+    comglue::{
+        interface::IID_IDISPATCH,
+        variant::{str_to_wstr, Variant},
+    },
+    server::UpdateNotify,
 };
 use anyhow::{anyhow, Result};
 use log::{debug, error};
-use std::{boxed::Box, ffi::c_void, sync::mpsc, thread, time::Duration};
+use std::{
+    boxed::Box,
+    ffi::c_void,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc,
+    },
+    thread,
+    time::Duration,
+};
 use windows::{
     core::{GUID, PCWSTR},
     Win32::System::{
@@ -25,6 +37,7 @@ use windows::{
 struct IRTDUpdateEventThreadArgs {
     stream: IStream,
     rx: mpsc::Receiver<()>,
+    alive: Arc<AtomicBool>,
 }
 
 static IDISPATCH_GUID: GUID = GUID {
@@ -75,6 +88,13 @@ unsafe fn irtd_update_event_loop(
 
 unsafe extern "system" fn irtd_update_event_thread(ptr: *mut c_void) -> u32 {
     let args = Box::from_raw(ptr.cast::<IRTDUpdateEventThreadArgs>());
+    let alive = args.alive.clone();
+    let res = update_event_thread(args);
+    alive.store(false, Ordering::Relaxed);
+    res
+}
+
+unsafe fn update_event_thread(args: Box<IRTDUpdateEventThreadArgs>) -> u32 {
     match CoInitialize(None) {
         hr if hr.is_err() => {
             error!("update_event_thread: failed to initialize COM {hr}");
@@ -82,7 +102,7 @@ unsafe extern "system" fn irtd_update_event_thread(ptr: *mut c_void) -> u32 {
         }
         _ => (),
     }
-    let IRTDUpdateEventThreadArgs { stream, rx } = *args;
+    let IRTDUpdateEventThreadArgs { stream, rx, alive: _ } = *args;
     let idp: Com::IDispatch = match CoGetInterfaceAndReleaseStream(&stream) {
         Ok(i) => i,
         Err(e) => {
@@ -118,14 +138,19 @@ unsafe extern "system" fn irtd_update_event_thread(ptr: *mut c_void) -> u32 {
     0
 }
 
-pub struct IRTDUpdateEventWrap(mpsc::Sender<()>);
+pub struct IRTDUpdateEventWrap {
+    tx: mpsc::Sender<()>,
+    alive: Arc<AtomicBool>,
+}
 
 impl IRTDUpdateEventWrap {
     pub unsafe fn new(disp: Com::IDispatch) -> Result<Self> {
         let (tx, rx) = mpsc::channel();
         let stream = CoMarshalInterThreadInterfaceInStream(&IDISPATCH_GUID, &disp)
             .map_err(|e| anyhow!(e.to_string()))?;
-        let args = Box::new(IRTDUpdateEventThreadArgs { stream, rx });
+        let alive = Arc::new(AtomicBool::new(true));
+        let args =
+            Box::new(IRTDUpdateEventThreadArgs { stream, rx, alive: alive.clone() });
         CreateThread(
             None,
             0,
@@ -134,10 +159,16 @@ impl IRTDUpdateEventWrap {
             THREAD_CREATION_FLAGS::default(),
             None,
         )?;
-        Ok(IRTDUpdateEventWrap(tx))
+        Ok(IRTDUpdateEventWrap { tx, alive })
+    }
+}
+
+impl UpdateNotify for IRTDUpdateEventWrap {
+    fn update_notify(&self) {
+        let _ = self.tx.send(());
     }
 
-    pub fn update_notify(&self) {
-        let _ = self.0.send(());
+    fn is_alive(&self) -> bool {
+        self.alive.load(Ordering::Relaxed)
     }
 }
@@ -4,15 +4,13 @@ use crate::{
         interface::{IDispatch, IRTDServer, IRTDUpdateEvent},
         variant::{string_from_wstr, SafeArray, Variant},
     },
-    server::{Server, TopicId},
+    server::{Cell, Server, TopicId},
+    xll_utils::XlErr,
 };
 use anyhow::{bail, Result};
 use com::sys::{HRESULT, IID, NOERROR};
-use log::{debug, error};
-use netidx::{
-    path::Path,
-    subscriber::{Event, Value},
-};
+use log::{debug, error, warn};
+use netidx::{path::Path, subscriber::Value};
 use windows::Win32::System::{
     Com::{ITypeInfo, DISPPARAMS, EXCEPINFO, SAFEARRAY, SAFEARRAYBOUND},
     Variant::VARIANT,
@@ -62,22 +60,27 @@ impl Params {
 }
 
 unsafe fn dispatch_server_start(server: &Server, params: Params) -> Result<()> {
-    server.server_start(IRTDUpdateEventWrap::new(params.get(0)?.try_into()?)?);
-    Ok(())
+    server.server_start(IRTDUpdateEventWrap::new(params.get(0)?.try_into()?)?)
 }
 
 unsafe fn dispatch_connect_data(server: &Server, params: Params) -> Result<()> {
     let topic_id = TopicId(params.get(2)?.try_into()?);
     let topics: &SafeArray = params.get(1)?.try_into()?;
     let topics = topics.read()?;
-    let path = match topics.iter()?.next() {
+    let mut topics = topics.iter()?;
+    let path = match topics.next() {
         None => bail!("not enough topics"),
         Some(v) => {
             let path: String = v.try_into()?;
             Path::from(path)
         }
     };
-    Ok(server.connect_data(topic_id, path)?)
+    let topics = topics
+        .map(|v| -> Result<String> { v.try_into() })
+        .collect::<Result<Vec<String>>>()?;
+    let mut options = server.default_options();
+    options.apply(&topics)?;
+    Ok(server.connect_data(topic_id, path, options)?)
 }
 
 fn variant_of_value(v: &Value) -> Variant {
@@ -88,17 +91,22 @@ fn variant_of_value(v: &Value) -> Variant {
         Value::U64(v) | Value::V64(v) => Variant::from(*v),
         Value::F32(v) => Variant::from(*v),
         Value::F64(v) => Variant::from(*v),
-        Value::Decimal(d) => match f64::try_from(*d) {
-            Ok(v) => Variant::from(v),
-            Err(e) => Variant::from(&format!("ERR {}", e)),
-        },
+        // the options convert decimals to doubles or text unless they
+        // asked for a COM decimal
+        Value::Decimal(d) => Variant::decimal(
+            d.is_sign_negative(),
+            d.scale() as u8,
+            d.mantissa().unsigned_abs(),
+        ),
         Value::True => Variant::from(true),
         Value::False => Variant::from(false),
         Value::String(s) => Variant::from(&**s),
         Value::Bytes(_) => Variant::from("#BIN"),
         Value::Null => Variant::null(),
         Value::Ok => Variant::from("OK"),
-        Value::Error(e) => Variant::from(&format!("#ERR {}", &**e)),
+        Value::Error(_) => Variant::error(XlErr::Value),
+        // native topics have these as serial numbers already, see
+        // TopicOptions::format
         Value::DateTime(d) => Variant::from(&d.to_string()),
         Value::Duration(d) => Variant::from(&format!("{}s", d.as_secs_f64())),
         Value::Array(_) => Variant::from(&format!("{}", v)),
@@ -117,10 +125,14 @@ fn variant_of_value(v: &Value) -> Variant {
     }
 }
 
-fn variant_of_event(e: &Event) -> Variant {
-    match e {
-        Event::Unsubscribed => Variant::from("#SUB"),
-        Event::Update(v) => variant_of_value(v),
+fn variant_of_cell(c: &Cell) -> Variant {
+    match c {
+        Cell::Unsubscribed => Variant::from("#SUB"),
+        Cell::Update(v) => variant_of_value(v),
+        Cell::Stale(v) => Variant::from(&format!("#STALE {}", v.to_string_naked())),
+        Cell::NA => Variant::error(XlErr::NA),
+        Cell::Error(e) => Variant::error((*e).into()),
+        Cell::Currency(c) => Variant::currency(*c),
     }
 }
 
@@ -142,7 +154,7 @@ unsafe fn dispatch_refresh_data(
         let mut wh = array.write()?;
         for (i, (TopicId(tid), e)) in updates.drain().enumerate() {
             *wh.get_mut(&[0, i as i32])? = Variant::from(tid);
-            *wh.get_mut(&[1, i as i32])? = variant_of_event(&e);
+            *wh.get_mut(&[1, i as i32])? = variant_of_cell(&e);
         }
     }
     *result = Variant::from(array);
@@ -210,7 +222,6 @@ com::class! {
             exception: *mut EXCEPINFO,
             arg_error: *mut u32
         ) -> HRESULT {
-            use crate::xll_utils::XlErr;
             debug!(
                 "invoke(id: {}, iid: {:?}, lcid: {}, flags: {}, params: {:?}, result: {:?}, exception: {:?}, arg_error: {:?})",
                 id, iid, lcid, flags, params, result, exception, arg_error
@@ -231,7 +242,7 @@ com::class! {
                     match dispatch_server_start(&self.server, params) {
                         Ok(()) => { *result = Variant::from(1); },
                         Err(e) => {
-                            error!("server_start invalid arg {}", e);
+                            error!("server_start failed {}", e);
                             *result = Variant::error(XlErr::NA);
                         }
                     }
@@ -273,7 +284,13 @@ com::class! {
                 },
                 5 => {
                     debug!("Heartbeat");
-                    *result = Variant::from(1);
+                    let health = self.server.heartbeat();
+                    if health.ok() {
+                        *result = Variant::from(1);
+                    } else {
+                        warn!("Heartbeat: unhealthy {:?}", health);
+                        *result = Variant::from(0);
+                    }
                 },
                 _ => {
                     debug!("unknown method {} called", id)
@@ -1,40 +1,147 @@
+#[cfg(windows)]
 pub(crate) mod dispatch;
+#[cfg(windows)]
 pub(crate) mod glue;
+#[cfg(windows)]
 pub(crate) mod interface;
+#[cfg(windows)]
 pub(crate) mod variant;
 
+use crate::{
+    errors::ErrorRule,
+    options::{BigInt, Bytes, Decimal, Stale, Tz},
+};
 use anyhow::Result;
-use dirs;
-use log::LevelFilter;
-use simplelog;
+use log::{error, info, LevelFilter};
+use parking_lot::Mutex;
 use std::{
+    collections::BTreeMap,
     default::Default,
     fs::{self, File},
-    path::PathBuf,
-    sync::LazyLock,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock},
+    thread,
+    time::{Duration, SystemTime},
 };
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// How often the config file is checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Auth {
     Anonymous,
     Kerberos,
     Tls,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// How to authenticate to a resolver server, as in a netidx config
+/// file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResolverAuth {
+    #[default]
+    Anonymous,
+    Krb5(String),
+    Local(String),
+    Tls(String),
+}
+
+/// A resolver server to use instead of the ones in a netidx config
+/// file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Resolver {
+    pub addr: SocketAddr,
+    #[serde(default)]
+    pub auth: ResolverAuth,
+}
+
+/// The netidx settings of a named connection profile, which formulas
+/// address by prefixing a path with the name, as in `uat:/foo/bar`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Profile {
+    #[serde(default)]
+    pub auth_mechanism: Option<Auth>,
+    #[serde(default)]
+    pub netidx_config: Option<PathBuf>,
+    #[serde(default)]
+    pub resolvers: Vec<Resolver>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub log_level: LevelFilter,
     #[serde(default)]
     pub auth_mechanism: Option<Auth>,
+    /// the netidx config file to use instead of the platform default
+    #[serde(default)]
+    pub netidx_config: Option<PathBuf>,
+    /// the resolver servers to use instead of a netidx config file
+    #[serde(default)]
+    pub resolvers: Vec<Resolver>,
+    /// the named connection profiles, each with a connection of its own
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// the stale mode of topics that don't specify one
+    #[serde(default)]
+    pub stale: Stale,
+    /// the time zone of topics that don't specify one
+    #[serde(default)]
+    pub tz: Tz,
+    /// which Excel error to show for which netidx error, see
+    /// `crate::errors`
+    #[serde(default)]
+    pub errors: Vec<ErrorRule>,
+    /// how topics that don't specify otherwise show bytes, decimals
+    /// and 64 bit integers
+    #[serde(default)]
+    pub bytes: Bytes,
+    #[serde(default)]
+    pub decimal: Decimal,
+    #[serde(default)]
+    pub bigint: BigInt,
+    /// make `=NetSet()` write a value again even if it was the last
+    /// value it wrote to the path
+    #[serde(default)]
+    pub write_repeats: bool,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Config { log_level: LevelFilter::Off, auth_mechanism: None }
+        Config {
+            log_level: LevelFilter::Off,
+            auth_mechanism: None,
+            netidx_config: None,
+            resolvers: Vec::new(),
+            profiles: BTreeMap::new(),
+            stale: Stale::default(),
+            tz: Tz::default(),
+            errors: Vec::new(),
+            bytes: Bytes::default(),
+            decimal: Decimal::default(),
+            bigint: BigInt::default(),
+            write_repeats: false,
+        }
     }
 }
 
-fn load_config_and_init_log() -> Result<Config> {
+impl Config {
+    /// The settings of the profile `name`, or the settings at the top
+    /// level of the config if `name` is None. None if there is no such
+    /// profile.
+    pub(crate) fn profile(&self, name: Option<&str>) -> Option<Profile> {
+        match name {
+            Some(name) => self.profiles.get(name).cloned(),
+            None => Some(Profile {
+                auth_mechanism: self.auth_mechanism,
+                netidx_config: self.netidx_config.clone(),
+                resolvers: self.resolvers.clone(),
+            }),
+        }
+    }
+}
+
+#[cfg(not(test))]
+fn config_dir() -> Result<PathBuf> {
     let path = match dirs::config_dir() {
         Some(d) => d,
         None => match dirs::home_dir() {
@@ -44,19 +151,86 @@ fn load_config_and_init_log() -> Result<Config> {
     };
     let base = path.join("netidx-excel");
     fs::create_dir_all(base.clone())?;
-    let config_file = base.join("config.json");
-    let log_file = base.join("log.txt");
+    Ok(base)
+}
+
+/// Tests keep their config out of the user's
+#[cfg(test)]
+pub(crate) fn config_dir() -> Result<PathBuf> {
+    let base =
+        std::env::temp_dir().join(format!("netidx-excel-test-{}", std::process::id()));
+    fs::create_dir_all(base.clone())?;
+    Ok(base)
+}
+
+/// Read the config file, creating it with the default config if it
+/// doesn't exist.
+pub(crate) fn load_config() -> Result<Config> {
+    let config_file = config_dir()?.join("config.json");
     if !config_file.exists() {
         fs::write(&*config_file, &serde_json::to_string_pretty(&Config::default())?)?;
     }
-    let config: Config = serde_json::from_str(&fs::read_to_string(config_file.clone())?)?;
-    let log = File::create(log_file)?;
-    simplelog::WriteLogger::init(config.log_level, simplelog::Config::default(), log)?;
+    Ok(serde_json::from_str(&fs::read_to_string(config_file)?)?)
+}
+
+/// The logger lets everything through, the log level of the config is
+/// the max level of the log crate, so that it can be changed without
+/// replacing the logger
+fn load_config_and_init_log() -> Result<Config> {
+    let config = load_config()?;
+    let log = File::create(config_dir()?.join("log.txt"))?;
+    simplelog::WriteLogger::init(LevelFilter::Trace, simplelog::Config::default(), log)?;
+    log::set_max_level(config.log_level);
     Ok(config)
 }
 
-pub static CONFIG: LazyLock<Config> =
-    LazyLock::new(|| match load_config_and_init_log() {
-        Ok(c) => c,
-        Err(_) => Config::default(),
+static CONFIG: LazyLock<Mutex<Arc<Config>>> = LazyLock::new(|| {
+    let config = load_config_and_init_log().unwrap_or_default();
+    if let Ok(dir) = config_dir() {
+        watch(dir.join("config.json"), WATCH_INTERVAL, || {
+            if let Err(e) = reload() {
+                error!("could not reload the changed config {}", e)
+            }
+        })
+    }
+    Mutex::new(Arc::new(config))
+});
+
+/// The add-in config as of the last time it was read
+pub(crate) fn config() -> Arc<Config> {
+    CONFIG.lock().clone()
+}
+
+/// Read the config file again. The log level takes effect now, topic
+/// settings for topics connected from now on, and auth when the RTD
+/// server is next started.
+pub(crate) fn reload() -> Result<Arc<Config>> {
+    let config = Arc::new(load_config()?);
+    log::set_max_level(config.log_level);
+    *CONFIG.lock() = config.clone();
+    info!("config reloaded");
+    Ok(config)
+}
+
+/// The modification time and size of `path`, to tell when it changes
+fn stamp(path: &Path) -> Option<(SystemTime, u64)> {
+    let meta = fs::metadata(path).ok()?;
+    Some((meta.modified().ok()?, meta.len()))
+}
+
+/// Call `changed` from a background thread whenever the file at `path`
+/// changes, checking every `interval`
+pub(crate) fn watch<F>(path: PathBuf, interval: Duration, mut changed: F)
+where
+    F: FnMut() + Send + 'static,
+{
+    let mut last = stamp(&path);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let now = stamp(&path);
+        if now != last {
+            last = now;
+            changed()
+        }
     });
+}
@@ -12,10 +12,10 @@ use std::{
 use windows::{
     core::PCWSTR,
     Win32::{
-        Foundation::SysAllocStringLen,
+        Foundation::{SysAllocStringLen, DECIMAL, DECIMAL_0, DECIMAL_0_0, DECIMAL_1},
         Globalization::lstrlenW,
         System::{
-            Com::{IDispatch, SAFEARRAY, SAFEARRAYBOUND},
+            Com::{IDispatch, CY, SAFEARRAY, SAFEARRAYBOUND},
             Ole::{
                 SafeArrayCreate, SafeArrayDestroy, SafeArrayGetDim, SafeArrayGetLBound,
                 SafeArrayGetUBound, SafeArrayGetVartype, SafeArrayLock,
@@ -23,8 +23,8 @@ use windows::{
             },
             Variant::{
                 VariantClear, VariantInit, VARENUM, VARIANT, VARIANT_0_0_0, VT_ARRAY,
-                VT_BOOL, VT_BSTR, VT_BYREF, VT_DISPATCH, VT_ERROR, VT_I4, VT_I8, VT_NULL,
-                VT_R4, VT_R8, VT_UI4, VT_UI8, VT_VARIANT,
+                VT_BOOL, VT_BSTR, VT_BYREF, VT_CY, VT_DECIMAL, VT_DISPATCH, VT_ERROR,
+                VT_I4, VT_I8, VT_NULL, VT_R4, VT_R8, VT_UI4, VT_UI8, VT_VARIANT,
             },
         },
     },
@@ -293,6 +293,32 @@ impl Variant {
         v
     }
 
+    /// A VT_DECIMAL of `mantissa / 10^scale`, the mantissa is 96 bits
+    pub fn decimal(negative: bool, scale: u8, mantissa: u128) -> Variant {
+        let mut v = Self::new();
+        // the decimal overlays the whole variant, including the type
+        v.0.Anonymous.decVal = DECIMAL {
+            wReserved: 0,
+            Anonymous1: DECIMAL_0 {
+                Anonymous: DECIMAL_0_0 { scale, sign: if negative { 0x80 } else { 0 } },
+            },
+            Hi32: (mantissa >> 64) as u32,
+            Anonymous2: DECIMAL_1 { Lo64: mantissa as u64 },
+        };
+        unsafe { v.set_typ(VT_DECIMAL) }
+        v
+    }
+
+    /// A VT_CY of `c / 10000`
+    pub fn currency(c: i64) -> Variant {
+        let mut v = Self::new();
+        unsafe {
+            v.set_typ(VT_CY);
+            v.val_mut().cyVal = CY { int64: c };
+        }
+        v
+    }
+
     pub fn as_ptr(&self) -> *const VARIANT {
         unsafe { mem::transmute::<&Variant, &VARIANT>(self) as *const VARIANT }
     }
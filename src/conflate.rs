@@ -0,0 +1,221 @@
+use anyhow::{bail, Error, Result};
+use chrono::TimeDelta;
+use netidx::subscriber::{Event, Typ, Value};
+use rust_decimal::Decimal;
+use std::{cmp, iter, mem, str::FromStr};
+
+/// How the updates to a topic that arrive between two calls to
+/// `refresh_data` are combined into the single value Excel sees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Conflation {
+    /// the last update in the window (the default)
+    #[default]
+    Last,
+    /// the first update in the window
+    First,
+    /// the largest update in the window
+    Max,
+    /// the smallest update in the window
+    Min,
+    /// the sum of the updates in the window
+    Sum,
+    /// the number of updates in the window
+    Count,
+    /// the volume weighted average price of the window, updates must
+    /// be [price, volume] pairs
+    Vwap,
+}
+
+impl FromStr for Conflation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "last" => Ok(Conflation::Last),
+            "first" => Ok(Conflation::First),
+            "max" => Ok(Conflation::Max),
+            "min" => Ok(Conflation::Min),
+            "sum" => Ok(Conflation::Sum),
+            "count" => Ok(Conflation::Count),
+            "vwap" => Ok(Conflation::Vwap),
+            _ => bail!("unknown conflation mode {}", s),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Acc {
+    Unsubscribed,
+    Value(Value),
+    Count(u64),
+    Vwap { pv: f64, volume: f64 },
+}
+
+/// The updates to one topic accumulated since the last refresh
+#[derive(Debug)]
+pub(crate) struct Conflated {
+    mode: Conflation,
+    acc: Acc,
+}
+
+fn price_volume(v: &Value) -> Option<(f64, f64)> {
+    match v {
+        Value::Array(a) if a.len() == 2 => {
+            let price = a[0].clone().cast_to::<f64>().ok()?;
+            let volume = a[1].clone().cast_to::<f64>().ok()?;
+            Some((price, volume))
+        }
+        _ => None,
+    }
+}
+
+fn integer(v: &Value) -> Option<i128> {
+    match v {
+        Value::U32(i) | Value::V32(i) => Some(*i as i128),
+        Value::I32(i) | Value::Z32(i) => Some(*i as i128),
+        Value::U64(i) | Value::V64(i) => Some(*i as i128),
+        Value::I64(i) | Value::Z64(i) => Some(*i as i128),
+        _ => None,
+    }
+}
+
+/// A number as a decimal, for adding to a decimal
+fn decimal(v: &Value) -> Option<Decimal> {
+    match v {
+        Value::F32(f) => Decimal::try_from(*f).ok(),
+        Value::F64(f) => Decimal::try_from(*f).ok(),
+        v => Decimal::try_from_i128_with_scale(integer(v)?, 0).ok(),
+    }
+}
+
+/// Add two updates, producing an error instead of wrapping or
+/// panicking when the sum overflows its type. Otherwise the sum is the
+/// same as Value's Add.
+fn checked_sum(l: Value, r: Value) -> Value {
+    let unbool = |v| match v {
+        Value::True => Value::U32(1),
+        Value::False => Value::U32(0),
+        v => v,
+    };
+    let (l, r) = (unbool(l), unbool(r));
+    let sum = match (&l, &r) {
+        (Value::String(s), _) => match s.parse::<Value>() {
+            Ok(l) => return checked_sum(l, r),
+            Err(_) => return l + r,
+        },
+        (_, Value::String(s)) => match s.parse::<Value>() {
+            Ok(r) => return checked_sum(l, r),
+            Err(_) => return l + r,
+        },
+        (Value::U32(l) | Value::V32(l), Value::U32(r) | Value::V32(r)) => {
+            l.checked_add(*r).map(Value::U32)
+        }
+        (Value::I32(l) | Value::Z32(l), Value::I32(r) | Value::Z32(r)) => {
+            l.checked_add(*r).map(Value::I32)
+        }
+        (Value::U64(l) | Value::V64(l), Value::U64(r) | Value::V64(r)) => {
+            l.checked_add(*r).map(Value::U64)
+        }
+        (Value::U32(l) | Value::V32(l), Value::U64(r) | Value::V64(r))
+        | (Value::U64(r) | Value::V64(r), Value::U32(l) | Value::V32(l)) => {
+            r.checked_add(*l as u64).map(Value::U64)
+        }
+        (Value::Decimal(l), Value::Decimal(r)) => l.checked_add(*r).map(Value::Decimal),
+        (Value::Duration(l), Value::Duration(r)) => {
+            l.checked_add(*r).map(Value::Duration)
+        }
+        (Value::DateTime(t), Value::Duration(d))
+        | (Value::Duration(d), Value::DateTime(t)) => TimeDelta::from_std(*d)
+            .ok()
+            .and_then(|d| t.checked_add_signed(d))
+            .map(Value::DateTime),
+        // arrays are summed element by element, the shorter one padded
+        // with zeros, and other values are summed as arrays of one
+        (Value::Array(l), Value::Array(r)) => {
+            let (short, long) = if l.len() < r.len() { (l, r) } else { (r, l) };
+            let short = short.iter().cloned().chain(iter::repeat(Value::F64(0.)));
+            let sum = short.zip(long.iter().cloned()).map(|(l, r)| checked_sum(l, r));
+            return Value::Array(sum.collect());
+        }
+        (Value::Array(_), _) | (_, Value::Array(_)) => {
+            return match (l.cast(Typ::Array), r.cast(Typ::Array)) {
+                (Some(l), Some(r)) => checked_sum(l, r),
+                (_, _) => Value::Error("can't add to array".into()),
+            };
+        }
+        (Value::Decimal(d), v) | (v, Value::Decimal(d)) => match decimal(v) {
+            Some(v) => d.checked_add(v).map(Value::Decimal),
+            None => return l + r,
+        },
+        // every other mix of integers sums to an i64, as Value's Add does
+        (li, ri) => match (integer(li), integer(ri)) {
+            (Some(li), Some(ri)) => i64::try_from(li + ri).ok().map(Value::I64),
+            _ => return l + r,
+        },
+    };
+    sum.unwrap_or_else(|| Value::Error("sum overflowed".into()))
+}
+
+impl Conflated {
+    pub(crate) fn new(mode: Conflation, ev: Event) -> Self {
+        let mut t = Conflated { mode, acc: Acc::Unsubscribed };
+        t.push(ev);
+        t
+    }
+
+    fn start(&self, v: Value) -> Acc {
+        match self.mode {
+            Conflation::Last
+            | Conflation::First
+            | Conflation::Max
+            | Conflation::Min
+            | Conflation::Sum => Acc::Value(v),
+            Conflation::Count => Acc::Count(1),
+            Conflation::Vwap => match price_volume(&v) {
+                Some((price, volume)) => Acc::Vwap { pv: price * volume, volume },
+                None => Acc::Value(Value::Error("vwap expects [price, volume]".into())),
+            },
+        }
+    }
+
+    /// Add an event to the window. Unsubscribed always replaces
+    /// whatever has accumulated, and the next update starts a new
+    /// accumulation.
+    pub(crate) fn push(&mut self, ev: Event) {
+        let v = match ev {
+            Event::Unsubscribed => {
+                self.acc = Acc::Unsubscribed;
+                return;
+            }
+            Event::Update(v) => v,
+        };
+        self.acc = match (self.mode, mem::replace(&mut self.acc, Acc::Unsubscribed)) {
+            (_, Acc::Unsubscribed) => self.start(v),
+            (Conflation::Last, _) => Acc::Value(v),
+            (Conflation::First, acc) => acc,
+            (Conflation::Max, Acc::Value(cur)) => Acc::Value(cmp::max(cur, v)),
+            (Conflation::Min, Acc::Value(cur)) => Acc::Value(cmp::min(cur, v)),
+            (Conflation::Sum, Acc::Value(cur)) => Acc::Value(checked_sum(cur, v)),
+            (Conflation::Count, Acc::Count(n)) => Acc::Count(n + 1),
+            (Conflation::Vwap, Acc::Vwap { pv, volume }) => match price_volume(&v) {
+                Some((p, q)) => Acc::Vwap { pv: pv + p * q, volume: volume + q },
+                None => Acc::Value(Value::Error("vwap expects [price, volume]".into())),
+            },
+            // an error in the window sticks until the next refresh
+            (_, acc) => acc,
+        }
+    }
+
+    /// The value to show in the cell
+    pub(crate) fn finish(self) -> Event {
+        match self.acc {
+            Acc::Unsubscribed => Event::Unsubscribed,
+            Acc::Value(v) => Event::Update(v),
+            Acc::Count(n) => Event::Update(Value::I64(n as i64)),
+            Acc::Vwap { volume: 0., .. } => {
+                Event::Update(Value::Error("vwap volume is zero".into()))
+            }
+            Acc::Vwap { pv, volume } => Event::Update(Value::F64(pv / volume)),
+        }
+    }
+}
@@ -0,0 +1,284 @@
+//! The connection to netidx, a runtime, a subscriber and a publisher,
+//! shared by the RTD server and `=NetSet()` so that reads, writes and
+//! publications use the same netidx config, auth and resolver. There
+//! is one for the default settings of the add-in config, and one for
+//! each named profile that a formula uses, by prefixing a path with
+//! the name of the profile, as in `uat:/foo/bar`.
+use crate::comglue;
+use anyhow::{bail, Result};
+use fxhash::FxHashMap;
+use log::{debug, info, warn};
+use netidx::{
+    config::{self, Config, DefaultAuthMech},
+    path::Path,
+    publisher::Publisher,
+    resolver_client::ResolverRead,
+    subscriber::{DesiredAuth, Subscriber},
+};
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, LazyLock,
+    },
+    time::Duration,
+};
+use tokio::{runtime::Runtime, time};
+
+#[cfg(not(test))]
+const RESOLVER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+#[cfg(not(test))]
+const RESOLVER_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+#[cfg(test)]
+const RESOLVER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+#[cfg(test)]
+const RESOLVER_CHECK_TIMEOUT: Duration = Duration::from_secs(1);
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+const MAX_CLIENTS: usize = 768;
+const SLACK: usize = 3;
+
+/// Split the profile off `path`, as in `uat:/foo/bar`, returning the
+/// name of the profile and the netidx path. Paths without a prefix are
+/// in the default profile. The root of a profile, `uat:/`, is `uat:`
+/// once the path is canonical.
+pub(crate) fn split_profile(path: &Path) -> (Option<&str>, Path) {
+    let split = match path.split_once(":/") {
+        Some((name, rest)) => Some((name, format!("/{}", rest))),
+        None => path.strip_suffix(':').map(|name| (name, String::from("/"))),
+    };
+    match split {
+        Some((name, rest)) if !name.is_empty() && !name.contains('/') => {
+            (Some(name), Path::from(rest))
+        }
+        Some(_) | None => (None, path.clone()),
+    }
+}
+
+/// The auth mechanism the profile asks for, or the default of the
+/// netidx config if it doesn't ask for one
+pub(crate) fn auth(cfg: &comglue::Profile, config: &Config) -> DesiredAuth {
+    match cfg.auth_mechanism {
+        None => config.default_auth(),
+        Some(comglue::Auth::Anonymous) => DesiredAuth::Anonymous,
+        Some(comglue::Auth::Kerberos) => DesiredAuth::Krb5 { upn: None, spn: None },
+        Some(comglue::Auth::Tls) => DesiredAuth::Tls { identity: None },
+    }
+}
+
+/// The netidx config the profile asks for. That is the resolvers it
+/// lists, or the netidx config file it names, or the platform default
+/// netidx config file.
+pub(crate) fn netidx_config(cfg: &comglue::Profile) -> Result<Config> {
+    if !cfg.resolvers.is_empty() {
+        let auth = |r: &comglue::Resolver| match &r.auth {
+            comglue::ResolverAuth::Anonymous => config::file::Auth::Anonymous,
+            comglue::ResolverAuth::Krb5(spn) => config::file::Auth::Krb5(spn.clone()),
+            comglue::ResolverAuth::Local(path) => config::file::Auth::Local(path.clone()),
+            comglue::ResolverAuth::Tls(name) => config::file::Auth::Tls(name.clone()),
+        };
+        // the auth mechanism of the add-in config if it has one,
+        // otherwise the one the first resolver uses
+        let default_auth = match (cfg.auth_mechanism, &cfg.resolvers[0].auth) {
+            (Some(comglue::Auth::Anonymous), _) => DefaultAuthMech::Anonymous,
+            (Some(comglue::Auth::Kerberos), _) => DefaultAuthMech::Krb5,
+            (Some(comglue::Auth::Tls), _) => DefaultAuthMech::Tls,
+            (None, comglue::ResolverAuth::Anonymous) => DefaultAuthMech::Anonymous,
+            (None, comglue::ResolverAuth::Krb5(_)) => DefaultAuthMech::Krb5,
+            (None, comglue::ResolverAuth::Local(_)) => DefaultAuthMech::Local,
+            (None, comglue::ResolverAuth::Tls(_)) => DefaultAuthMech::Tls,
+        };
+        return Config::from_file(config::file::Config {
+            base: "/".into(),
+            addrs: cfg.resolvers.iter().map(|r| (r.addr, auth(r))).collect(),
+            tls: None,
+            default_auth,
+            default_bind_config: None,
+        });
+    }
+    match &cfg.netidx_config {
+        Some(path) => Config::load(path),
+        None => Config::load_default(),
+    }
+}
+
+pub(crate) struct Connection {
+    pub(crate) runtime: Runtime,
+    pub(crate) subscriber: Subscriber,
+    /// whether the last check of the resolver succeeded
+    pub(crate) resolver_ok: Arc<AtomicBool>,
+    config: Config,
+    auth: DesiredAuth,
+    publisher: Mutex<Option<Publisher>>,
+}
+
+impl Connection {
+    pub(crate) fn new(config: Config, auth: DesiredAuth) -> Result<Arc<Connection>> {
+        debug!("init runtime");
+        let runtime = Runtime::new()?;
+        debug!("entering async to init subscriber");
+        let subscriber = runtime.block_on(async {
+            debug!("starting subscriber");
+            Subscriber::new(config.clone(), auth.clone())
+        })?;
+        // the resolver is presumed reachable until the first check fails
+        let resolver_ok = Arc::new(AtomicBool::new(true));
+        runtime.spawn(resolver_check_loop(subscriber.resolver(), resolver_ok.clone()));
+        Ok(Arc::new(Connection {
+            runtime,
+            subscriber,
+            resolver_ok,
+            config,
+            auth,
+            publisher: Mutex::new(None),
+        }))
+    }
+
+    /// The publisher, which is started the first time it is needed.
+    /// This must not be called from the runtime.
+    pub(crate) fn publisher(&self) -> Result<Publisher> {
+        let mut publisher = self.publisher.lock();
+        match &*publisher {
+            Some(publisher) => Ok(publisher.clone()),
+            None => {
+                debug!("starting publisher");
+                let p = self.runtime.block_on(Publisher::new(
+                    self.config.clone(),
+                    self.auth.clone(),
+                    self.config.default_bind_config,
+                    MAX_CLIENTS,
+                    SLACK,
+                ))?;
+                *publisher = Some(p.clone());
+                Ok(p)
+            }
+        }
+    }
+
+    /// Close all the subscriptions and publications and shut down the
+    /// runtime. This must not be called from the runtime.
+    pub(crate) fn shutdown(self) {
+        let Connection { runtime, subscriber, publisher, .. } = self;
+        drop(subscriber);
+        // the publisher clears its paths from the resolver on the
+        // runtime when it is dropped
+        let rt = runtime.enter();
+        drop(publisher);
+        drop(rt);
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        debug!("runtime shut down");
+    }
+}
+
+async fn resolver_check_loop(resolver: ResolverRead, ok: Arc<AtomicBool>) {
+    loop {
+        let res = time::timeout(RESOLVER_CHECK_TIMEOUT, resolver.list(Path::root()));
+        let up = match res.await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                debug!("resolver check failed {}", e);
+                false
+            }
+            Err(_) => {
+                debug!("resolver check timed out");
+                false
+            }
+        };
+        if ok.swap(up, Ordering::Relaxed) != up {
+            if up {
+                info!("resolver is reachable");
+            } else {
+                warn!("resolver is unreachable");
+            }
+        }
+        time::sleep(RESOLVER_CHECK_INTERVAL).await
+    }
+}
+
+/// The connections of the add-in by profile, None being the default,
+/// with the settings each was made from
+type Shared = FxHashMap<Option<String>, (comglue::Profile, Arc<Connection>)>;
+
+static SHARED: LazyLock<Mutex<Shared>> = LazyLock::new(|| Mutex::new(Shared::default()));
+
+/// The connection of the add-in for `profile`, or for the default
+/// settings if it is None. It is made from the add-in config the first
+/// time it is needed, and kept until `reconnect` replaces it or
+/// `release_unused` lets go of it. If making it fails it is tried again
+/// the next time.
+pub(crate) fn shared(profile: Option<&str>) -> Result<Arc<Connection>> {
+    let mut shared = SHARED.lock();
+    let key = profile.map(String::from);
+    match shared.get(&key) {
+        Some((_, conn)) => Ok(conn.clone()),
+        None => {
+            debug!("loading config file");
+            let cfg = comglue::config();
+            let settings = match cfg.profile(profile) {
+                Some(settings) => settings,
+                None => bail!("unknown profile {}", profile.unwrap_or_default()),
+            };
+            let config = netidx_config(&settings)?;
+            let auth = auth(&settings, &config);
+            let conn = Connection::new(config, auth)?;
+            shared.insert(key, (settings, conn.clone()));
+            Ok(conn)
+        }
+    }
+}
+
+/// Whether `conn` is one of the shared connections
+pub(crate) fn is_shared(conn: &Arc<Connection>) -> bool {
+    SHARED.lock().values().any(|(_, shared)| Arc::ptr_eq(shared, conn))
+}
+
+/// Let go of the shared connections whose settings changed in the
+/// add-in config since they were made, or whose profile is gone, so
+/// that `shared` makes new ones. Returns true if it did. The old
+/// connections are shut down when their last user releases them.
+pub(crate) fn reconnect() -> bool {
+    let cfg = comglue::config();
+    let mut shared = SHARED.lock();
+    let changed = shared
+        .iter()
+        .filter(|(profile, (settings, _))| {
+            cfg.profile(profile.as_deref()).as_ref() != Some(settings)
+        })
+        .map(|(profile, _)| profile.clone())
+        .collect::<Vec<_>>();
+    let old = changed.iter().filter_map(|p| shared.remove_entry(p)).collect::<Vec<_>>();
+    drop(shared);
+    let reconnected = !old.is_empty();
+    for (profile, (_, conn)) in old {
+        info!(
+            "the netidx settings of {} changed, the connection will be made again",
+            profile.as_deref().unwrap_or("the default profile")
+        );
+        release(conn);
+    }
+    reconnected
+}
+
+/// Let go of the shared connections that nothing else is using, and
+/// shut them down. `shared` makes them again when they are next
+/// needed.
+pub(crate) fn release_unused() {
+    let mut shared = SHARED.lock();
+    let unused = shared
+        .iter()
+        .filter(|(_, (_, conn))| Arc::strong_count(conn) == 1)
+        .map(|(profile, _)| profile.clone())
+        .collect::<Vec<_>>();
+    let old = unused.iter().filter_map(|p| shared.remove(p)).collect::<Vec<_>>();
+    drop(shared);
+    for (_, conn) in old {
+        release(conn)
+    }
+}
+
+/// Let go of `conn`, shutting it down if nothing else is using it.
+/// This must not be called from the runtime.
+pub(crate) fn release(conn: Arc<Connection>) {
+    if let Some(conn) = Arc::into_inner(conn) {
+        conn.shutdown()
+    }
+}
@@ -0,0 +1,97 @@
+//! Netidx errors are shown as Excel error values, so that ISERROR,
+//! IFERROR and conditional formatting work on them. Which error value
+//! is decided by the error rules in the config file, e.g.
+//!
+//! ```json
+//! "errors": [
+//!     { "pattern": "timed out", "error": "#N/A" },
+//!     { "pattern": "divide by zero", "error": "#DIV/0!" }
+//! ]
+//! ```
+//!
+//! The first rule whose pattern appears in the error message, ignoring
+//! case, wins. If no rule matches, a message that starts with the name
+//! of an Excel error is that error, and anything else is `#VALUE!`.
+//! The message itself can be shown with `=NetErrorText()`.
+
+/// An Excel error value
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExcelError {
+    #[serde(rename = "#NULL!")]
+    Null,
+    #[serde(rename = "#DIV/0!")]
+    Div0,
+    #[serde(rename = "#VALUE!")]
+    Value,
+    #[serde(rename = "#REF!")]
+    Ref,
+    #[serde(rename = "#NAME?")]
+    Name,
+    #[serde(rename = "#NUM!")]
+    Num,
+    #[serde(rename = "#N/A")]
+    NA,
+}
+
+impl ExcelError {
+    const ALL: [ExcelError; 7] = [
+        ExcelError::Null,
+        ExcelError::Div0,
+        ExcelError::Value,
+        ExcelError::Ref,
+        ExcelError::Name,
+        ExcelError::Num,
+        ExcelError::NA,
+    ];
+
+    /// The name Excel shows in the cell
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            ExcelError::Null => "#NULL!",
+            ExcelError::Div0 => "#DIV/0!",
+            ExcelError::Value => "#VALUE!",
+            ExcelError::Ref => "#REF!",
+            ExcelError::Name => "#NAME?",
+            ExcelError::Num => "#NUM!",
+            ExcelError::NA => "#N/A",
+        }
+    }
+}
+
+#[cfg(windows)]
+impl From<ExcelError> for crate::xll_utils::XlErr {
+    fn from(e: ExcelError) -> Self {
+        use crate::xll_utils::XlErr;
+        match e {
+            ExcelError::Null => XlErr::Null,
+            ExcelError::Div0 => XlErr::Div0,
+            ExcelError::Value => XlErr::Value,
+            ExcelError::Ref => XlErr::Ref,
+            ExcelError::Name => XlErr::Name,
+            ExcelError::Num => XlErr::Num,
+            ExcelError::NA => XlErr::NA,
+        }
+    }
+}
+
+/// Show errors whose message contains `pattern` as `error`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorRule {
+    pub pattern: String,
+    pub error: ExcelError,
+}
+
+/// The Excel error to show for the netidx error `msg`
+pub(crate) fn classify(rules: &[ErrorRule], msg: &str) -> ExcelError {
+    let lower = msg.to_lowercase();
+    for rule in rules {
+        if lower.contains(&rule.pattern.to_lowercase()) {
+            return rule.error;
+        }
+    }
+    let msg = msg.trim_start().to_uppercase();
+    ExcelError::ALL
+        .into_iter()
+        .find(|e| msg.starts_with(e.name()))
+        .unwrap_or(ExcelError::Value)
+}
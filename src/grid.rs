@@ -0,0 +1,61 @@
+use netidx::subscriber::Value;
+
+/// A rectangular range of cells in row major order
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Grid {
+    pub(crate) rows: usize,
+    pub(crate) columns: usize,
+    pub(crate) cells: Vec<Value>,
+}
+
+impl Grid {
+    /// Lay out a value as a range of cells. A scalar is a single
+    /// cell, an array is a column, and an array containing arrays is
+    /// a 2-D range with one row per element. Short rows are padded
+    /// with `Null`, and anything nested more deeply stays an array in
+    /// its cell.
+    pub(crate) fn of_value(v: &Value) -> Grid {
+        match v {
+            Value::Array(a) if a.is_empty() => {
+                Grid { rows: 1, columns: 1, cells: vec![Value::Null] }
+            }
+            Value::Array(a) if !a.iter().any(|v| matches!(v, Value::Array(_))) => {
+                Grid { rows: a.len(), columns: 1, cells: a.to_vec() }
+            }
+            Value::Array(a) => {
+                let width = |v: &Value| match v {
+                    Value::Array(a) => a.len(),
+                    _ => 1,
+                };
+                let columns = a.iter().map(width).max().unwrap_or(1).max(1);
+                let mut cells = Vec::with_capacity(a.len() * columns);
+                for row in a.iter() {
+                    match row {
+                        Value::Array(row) => cells.extend(row.iter().cloned()),
+                        v => cells.push(v.clone()),
+                    }
+                    cells.resize(cells.len() + columns - width(row), Value::Null)
+                }
+                Grid { rows: a.len(), columns, cells }
+            }
+            v => Grid { rows: 1, columns: 1, cells: vec![v.clone()] },
+        }
+    }
+
+    /// The value of a range. A single cell is a scalar, a single row
+    /// or column is an array, and anything else is an array of rows,
+    /// so that `of_value` lays the value out as the same range, except
+    /// that a row becomes a column.
+    pub(crate) fn into_value(self) -> Value {
+        match (self.rows, self.columns) {
+            (1, 1) if self.cells.len() == 1 => self.cells.into_iter().next().unwrap(),
+            (1, _) | (_, 1) => Value::from(self.cells),
+            (_, columns) => Value::from(
+                self.cells
+                    .chunks(columns.max(1))
+                    .map(|row| Value::from(row.to_vec()))
+                    .collect::<Vec<_>>(),
+            ),
+        }
+    }
+}
@@ -1,13 +1,48 @@
+// the core is only reachable from the Excel entry points, so on other
+// platforms it is only built for the tests
+#[cfg(any(windows, test))]
 #[macro_use]
 extern crate serde_derive;
+#[cfg(any(windows, test))]
 mod comglue;
+#[cfg(any(windows, test))]
+mod conflate;
+#[cfg(any(windows, test))]
+mod connection;
+#[cfg(any(windows, test))]
+mod errors;
+#[cfg(any(windows, test))]
+mod grid;
+#[cfg(any(windows, test))]
+mod options;
+#[cfg(any(windows, test))]
+mod publish;
+#[cfg(any(windows, test))]
+mod publish_table;
+#[cfg(any(windows, test))]
+mod rpc;
+#[cfg(any(windows, test))]
+mod serial;
+#[cfg(any(windows, test))]
 mod server;
+#[cfg(any(windows, test))]
+mod set_type;
+#[cfg(any(windows, test))]
+mod table;
+#[cfg(test)]
+mod test;
+#[cfg(windows)]
 mod xll_utils;
+#[cfg(windows)]
 use anyhow::Result;
+#[cfg(windows)]
 use comglue::{glue::NetidxRTD, interface::CLSID};
+#[cfg(windows)]
 use xll_utils::xloper12;
+#[cfg(any(windows, test))]
 mod setter;
 
+#[cfg(windows)]
 #[no_mangle]
 extern "system" fn NetGet(path: xll_utils::LPXLOPER12) -> xll_utils::LPXLOPER12 {
     use xll_utils::*;
@@ -27,136 +62,296 @@ extern "system" fn NetGet(path: xll_utils::LPXLOPER12) -> xll_utils::LPXLOPER12
     }
 }
 
-#[no_mangle]
-extern "system" fn NetSet(
-    path: *const std::ffi::c_char,
-    value: xll_utils::LPXLOPER12,
-    ty: *const std::ffi::c_char,
+/// Call RTD with the path and extra topic strings, asking for the
+/// value in netidx syntax. None if the call failed.
+#[cfg(windows)]
+fn rtd_typed(
+    path: xll_utils::LPXLOPER12,
+    topics: &[xll_utils::LPXLOPER12],
+) -> Option<xll_utils::XLOper12> {
+    use xll_utils::*;
+    let mut res = XLOper12::error(XlErr::GettingData);
+    const CLASS_NAME: XLOper12 = xloper12_const_string!("NetidxRTD");
+    // RTD can only return scalars, so ask for the value in netidx
+    // syntax and parse it back out
+    const TYPED: XLOper12 = xloper12_const_string!("format=typed");
+    let mut args = vec![
+        CLASS_NAME.as_lpxloper12(),
+        XLOper12::missing().as_lpxloper12(),
+        path,
+        TYPED.as_lpxloper12(),
+    ];
+    args.extend_from_slice(topics);
+    match excel12v(Xlfn::xlfRtd, res.as_mut_xloper12(), &args) {
+        0 => {
+            res.set_xlfree();
+            Some(res)
+        }
+        _nonzero_ => None,
+    }
+}
+
+/// Spill a value returned by `rtd_typed`
+#[cfg(windows)]
+fn spill_typed(res: xll_utils::XLOper12) -> xll_utils::LPXLOPER12 {
+    use netidx::subscriber::Value;
+    use xll_utils::*;
+    let v = match String::try_from(&res) {
+        Err(_) => return res.into(),
+        Ok(s) => match s.parse::<Value>() {
+            Ok(v) => v,
+            Err(_) if s.starts_with("#SUB") || s.starts_with("#STALE") => {
+                return res.into()
+            }
+            // too long for Excel, which truncated it
+            Err(_) => return XLOper12::error(XlErr::Value).into(),
+        },
+    };
+    spill(&v).into()
+}
+
+/// Call RTD with the path and extra topic strings, and spill the value
+/// it returns
+#[cfg(windows)]
+fn rtd_array(
+    path: xll_utils::LPXLOPER12,
+    topics: &[xll_utils::LPXLOPER12],
 ) -> xll_utils::LPXLOPER12 {
+    use xll_utils::*;
+    match rtd_typed(path, topics) {
+        Some(res) => spill_typed(res),
+        None => XLOper12::error(XlErr::NA).into(),
+    }
+}
+
+/// A value as an array that spills into the sheet, with dates as
+/// serial numbers and errors as Excel errors
+#[cfg(windows)]
+fn spill(v: &netidx::subscriber::Value) -> xll_utils::XLOper12 {
     use netidx::subscriber::Value;
-    use std::ffi::CStr;
     use xll_utils::*;
+    let grid = grid::Grid::of_value(v);
+    let cfg = comglue::config();
+    let cells = grid
+        .cells
+        .into_iter()
+        .map(|v| match serial::native(v, cfg.tz) {
+            Value::Error(e) => XLOper12::error(errors::classify(&cfg.errors, &e).into()),
+            v => XLOper12::from(&v),
+        })
+        .collect();
+    XLOper12::multi(grid.rows, grid.columns, cells)
+}
 
-    const SET: XLOper12 = xloper12_const_string!("#SET");
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetGetArray(path: xll_utils::LPXLOPER12) -> xll_utils::LPXLOPER12 {
+    rtd_array(path, &[])
+}
 
-    static SETTER: std::sync::LazyLock<Option<setter::Setter>> =
-        std::sync::LazyLock::new(|| match setter::Setter::new() {
-            Err(e) => {
-                log::error!("Error creating Netidx setter: {e}");
-                None
-            }
-            Ok(setter) => Some(setter),
-        });
-
-    /// The type of data to publish
-    enum NetSetType {
-        Auto,
-        F64,
-        I64,
-        Null,
-        Time,
-        String,
-        Bool,
-    }
-
-    impl NetSetType {
-        fn apply(&self, raw: LPXLOPER12) -> Value {
-            match self {
-                NetSetType::Auto => Value::from(&unsafe { *raw }),
-                NetSetType::F64 => match f64::try_from(&unsafe { *raw }) {
-                    Ok(v) => Value::F64(v),
-                    Err(()) => Value::Error("#TYPE!".into()),
-                },
-                NetSetType::I64 => match i64::try_from(&unsafe { *raw }) {
-                    Ok(v) => Value::I64(v),
-                    Err(()) => Value::Error("#TYPE!".into()),
-                },
-                NetSetType::Null => Value::Null,
-                NetSetType::String => match String::try_from(&unsafe { *raw }) {
-                    Ok(s) => s.into(),
-                    Err(e) => Value::Error(e.to_string().into()),
-                },
-                NetSetType::Bool => match bool::try_from(&unsafe { *raw }) {
-                    Ok(v) => v.into(),
-                    Err(()) => Value::Error("#TYPE!".into()),
-                },
-                NetSetType::Time => {
-                    match f64::try_from(&unsafe { *raw }) {
-                        Ok(mut v) => {
-                            use chrono::{
-                                offset::LocalResult::*, Duration, NaiveDateTime,
-                                TimeZone as _,
-                            };
-                            // Excel's epoch is midnight on 1900-01-00 (i.e., 1899-12-31)
-                            const EXCEL_EPOCH: chrono::NaiveDateTime =
-                                chrono::NaiveDate::from_ymd_opt(1899, 12, 31)
-                                    .expect("never raises")
-                                    .and_hms_opt(0, 0, 0)
-                                    .expect("never raises");
-                            if v < 0.0 {
-                                Value::Error("#VALUE!".into())
-                            } else {
-                                if v > 59.0 {
-                                    // Due to a legacy bug, Excel treats Feb 1900 as having 29 days, so we need to subtract 1 for dates above this
-                                    // [https://learn.microsoft.com/en-us/office/troubleshoot/excel/wrongly-assumes-1900-is-leap-year]
-                                    v -= 1.0;
-                                }
-                                let date: chrono::NaiveDateTime =
-                                    EXCEL_EPOCH + chrono::Duration::days(v as i64);
-                                let milliseconds =
-                                    (v.fract() * 86_400.0 * 1_000.0) as i64; // convert to milliseconds * 24.0 * 60.0 * 60.0 * 1000
-                                let naive_time: NaiveDateTime =
-                                    date + Duration::milliseconds(milliseconds);
-
-                                match chrono::Local.from_local_datetime(&naive_time) {
-                                    Single(time) => Value::DateTime(time.to_utc()),
-                                    Ambiguous(_, _) => {
-                                        Value::Error("#AMBIGUOUS_TIME".into())
-                                    }
-                                    None => Value::Error("#VALUE!".into()),
-                                }
-                            }
-                        }
-                        Err(()) => Value::Error("#TYPE!".into()),
-                    }
-                }
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetTable(
+    base: xll_utils::LPXLOPER12,
+    columns: xll_utils::LPXLOPER12,
+    rows: xll_utils::LPXLOPER12,
+) -> xll_utils::LPXLOPER12 {
+    use xll_utils::{xloper12::ToStringError, *};
+    const TABLE: XLOper12 = xloper12_const_string!("table=true");
+    let names = |key: &str, x: LPXLOPER12| -> Result<Option<XLOper12>, ToStringError> {
+        let names = Vec::<String>::try_from(unsafe { &*x })?;
+        Ok(match names.is_empty() {
+            true => None,
+            false => Some(XLOper12::from(format!("{}={}", key, names.join(",")))),
+        })
+    };
+    match (names("columns", columns), names("rows", rows)) {
+        (Ok(columns), Ok(rows)) => {
+            let mut topics = vec![TABLE.as_lpxloper12()];
+            topics.extend(columns.iter().chain(rows.iter()).map(|x| x.as_lpxloper12()));
+            rtd_array(base, &topics)
+        }
+        (Err(_), _) | (_, Err(_)) => XLOper12::error(XlErr::Value).into(),
+    }
+}
+
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetList(
+    path: xll_utils::LPXLOPER12,
+    recursive: xll_utils::LPXLOPER12,
+    glob: xll_utils::LPXLOPER12,
+) -> xll_utils::LPXLOPER12 {
+    use netidx::subscriber::Value;
+    use xll_utils::*;
+    const LIST: XLOper12 = xloper12_const_string!("list=true");
+    const RECURSIVE: XLOper12 = xloper12_const_string!("recursive=true");
+    let recursive = match Value::from(unsafe { &*recursive }) {
+        Value::Null => Ok(false),
+        _ => bool::try_from(unsafe { &*recursive }),
+    };
+    let glob = Vec::<String>::try_from(unsafe { &*glob });
+    match (recursive, glob) {
+        (Ok(recursive), Ok(glob)) => {
+            let glob =
+                glob.into_iter().next().map(|g| XLOper12::from(format!("glob={}", g)));
+            let mut topics = vec![LIST.as_lpxloper12()];
+            if recursive {
+                topics.push(RECURSIVE.as_lpxloper12());
             }
+            topics.extend(glob.iter().map(|x| x.as_lpxloper12()));
+            rtd_array(path, &topics)
         }
+        (Err(_), _) | (_, Err(_)) => XLOper12::error(XlErr::Value).into(),
     }
+}
 
-    impl TryFrom<*const std::ffi::c_char> for NetSetType {
-        type Error = ();
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetLive(path: xll_utils::LPXLOPER12) -> xll_utils::LPXLOPER12 {
+    use xll_utils::*;
+    let mut res = XLOper12::error(XlErr::GettingData);
+    const CLASS_NAME: XLOper12 = xloper12_const_string!("NetidxRTD");
+    const LIVE: XLOper12 = xloper12_const_string!("live=true");
 
-        fn try_from(ptr: *const std::ffi::c_char) -> Result<Self, ()> {
-            match ptr.is_null() {
-                true => Ok(Self::Auto),
-                false => match unsafe { CStr::from_ptr(ptr) }.to_bytes() {
-                    b"" | b"auto" => Ok(Self::Auto),
-                    b"f64" => Ok(Self::F64),
-                    b"i64" => Ok(Self::I64),
-                    b"null" => Ok(Self::Null),
-                    b"time" => Ok(Self::Time),
-                    b"string" => Ok(Self::String),
-                    b"bool" => Ok(Self::Bool),
-                    _ => Err(()),
-                },
-            }
+    match excel12v(
+        Xlfn::xlfRtd,
+        res.as_mut_xloper12(),
+        &[
+            CLASS_NAME.as_lpxloper12(),
+            XLOper12::missing().as_lpxloper12(),
+            path,
+            LIVE.as_lpxloper12(),
+        ],
+    ) {
+        0 => {
+            res.set_xlfree();
+            res.into()
         }
+        _nonzero_ => XLOper12::error(XlErr::NA).into(),
     }
+}
+
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetErrorText(path: xll_utils::LPXLOPER12) -> xll_utils::LPXLOPER12 {
+    use xll_utils::*;
+    let mut res = XLOper12::error(XlErr::GettingData);
+    const CLASS_NAME: XLOper12 = xloper12_const_string!("NetidxRTD");
+    const ERROR_TEXT: XLOper12 = xloper12_const_string!("errortext=true");
 
+    match excel12v(
+        Xlfn::xlfRtd,
+        res.as_mut_xloper12(),
+        &[
+            CLASS_NAME.as_lpxloper12(),
+            XLOper12::missing().as_lpxloper12(),
+            path,
+            ERROR_TEXT.as_lpxloper12(),
+        ],
+    ) {
+        0 => {
+            res.set_xlfree();
+            res.into()
+        }
+        _nonzero_ => XLOper12::error(XlErr::NA).into(),
+    }
+}
+
+/// The setters by profile, with the connection each writes through.
+/// They don't keep the connection alive, so that it can be shut down
+/// when the RTD server terminates, a new setter is made when it is.
+#[cfg(windows)]
+type Setters = fxhash::FxHashMap<
+    Option<String>,
+    (std::sync::Weak<connection::Connection>, std::sync::Arc<setter::Setter>),
+>;
+
+#[cfg(windows)]
+static SETTERS: std::sync::LazyLock<parking_lot::Mutex<Setters>> =
+    std::sync::LazyLock::new(|| parking_lot::Mutex::new(Setters::default()));
+
+/// The setter of `NetSet` and `NetSetMany` for `profile`, which is made
+/// again when the shared connection of the profile is, None if the
+/// connection couldn't be made
+#[cfg(windows)]
+fn setter(profile: Option<&str>) -> Option<std::sync::Arc<setter::Setter>> {
+    use std::sync::Arc;
+    let key = profile.map(String::from);
+    let conn = match connection::shared(profile) {
+        Ok(conn) => conn,
+        Err(e) => {
+            log::error!("Error creating Netidx setter: {e}");
+            SETTERS.lock().remove(&key);
+            return None;
+        }
+    };
+    let mut setters = SETTERS.lock();
+    match setters.get(&key) {
+        Some((c, setter)) if c.as_ptr() == Arc::as_ptr(&conn) => Some(setter.clone()),
+        Some(_) | None => {
+            let setter =
+                Arc::new(setter::Setter::new(&conn, comglue::config().write_repeats));
+            setters.insert(key, (Arc::downgrade(&conn), setter.clone()));
+            Some(setter)
+        }
+    }
+}
+
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetSet(
+    path: *const std::ffi::c_char,
+    value: xll_utils::LPXLOPER12,
+    ty: *const std::ffi::c_char,
+    interval: xll_utils::LPXLOPER12,
+) -> xll_utils::LPXLOPER12 {
+    use netidx::subscriber::Value;
+    use std::{ffi::CStr, time::Duration};
+    use xll_utils::*;
+
+    const SET: XLOper12 = xloper12_const_string!("#SET");
+
+    let typ = match ty.is_null() {
+        true => Ok(set_type::SetType::Auto),
+        false => match unsafe { CStr::from_ptr(ty) }.to_str() {
+            Err(_) => Err(()),
+            Ok(ty) => ty.parse::<set_type::SetType>().map_err(|_| ()),
+        },
+    };
+    let min_interval = match Value::from(unsafe { &*interval }) {
+        Value::Null => Duration::ZERO,
+        _ => match f64::try_from(unsafe { &*interval }) {
+            Err(()) => return XLOper12::error(XlErr::Value).into(),
+            Ok(secs) => match Duration::try_from_secs_f64(secs) {
+                Ok(d) => d,
+                Err(_) => return XLOper12::error(XlErr::Num).into(),
+            },
+        },
+    };
     match unsafe { CStr::from_ptr(path) }.to_str() {
         Err(_) => XLOper12::error(XlErr::NA).into(),
-        Ok(s) => match NetSetType::try_from(ty) {
+        Ok(s) => match typ {
             Err(()) => XLOper12::error(XlErr::NA).into(),
             Ok(typ) => {
                 let path: netidx::path::Path = Into::<netidx::path::Path>::into(s);
-                let value = typ.apply(value);
-                match *SETTER {
+                let value = match typ
+                    .apply(Value::from(&unsafe { *value }), comglue::config().tz)
+                {
+                    Ok(value) => value,
+                    Err(e) => return XLOper12::error(e.into()).into(),
+                };
+                let setter = setter(connection::split_profile(&path).0);
+                match setter {
                     None => XLOper12::error(XlErr::NA).into(),
-                    Some(ref setter) => match setter.set(path, value) {
+                    Some(setter) => match setter.set(path, value, min_interval) {
                         Ok(()) => SET.as_lpxloper12(),
-                        Err(tokio::sync::mpsc::error::SendError((path, value))) => {
-                            log::error!("failure setting {value} at netidx path {path}");
+                        Err(tokio::sync::mpsc::error::SendError(writes)) => {
+                            for (path, value, _) in writes {
+                                log::error!(
+                                    "failure setting {value} at netidx path {path}"
+                                );
+                            }
                             XLOper12::error(XlErr::NA).into()
                         }
                     },
@@ -166,17 +361,460 @@ extern "system" fn NetSet(
     }
 }
 
+/// Write a value to each of a range of paths, all together, and show
+/// the status of each write, `#SKIPPED` for a value that was the last
+/// one written to its path. Nothing is written if any of the values
+/// can't be converted to its type, or if any of the profiles of the
+/// paths can't be connected.
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetSetMany(
+    paths: xll_utils::LPXLOPER12,
+    values: xll_utils::LPXLOPER12,
+    types: xll_utils::LPXLOPER12,
+) -> xll_utils::LPXLOPER12 {
+    use netidx::subscriber::Value;
+    use std::time::Duration;
+    use xll_utils::*;
+    let mut batch = match setter::batch(
+        &Value::from(unsafe { &*paths }),
+        &Value::from(unsafe { &*values }),
+        &Value::from(unsafe { &*types }),
+        comglue::config().tz,
+    ) {
+        Ok(batch) => batch,
+        Err(e) => return XLOper12::error(e.into()).into(),
+    };
+    // the writes to each profile are a batch of their own, with the
+    // index of each write to put its outcome back in order
+    let mut by_profile = fxhash::FxHashMap::<Option<String>, Vec<_>>::default();
+    for (i, (path, v)) in batch.writes.drain(..).enumerate() {
+        let profile = connection::split_profile(&path).0.map(String::from);
+        by_profile.entry(profile).or_default().push((i, (path, v, Duration::ZERO)));
+    }
+    // find every setter first, so nothing is written if one of them
+    // can't be made
+    let by_setter = by_profile
+        .into_iter()
+        .map(|(profile, writes)| setter(profile.as_deref()).map(|s| (s, writes)))
+        .collect::<Option<Vec<_>>>();
+    let by_setter = match by_setter {
+        Some(by_setter) => by_setter,
+        None => return XLOper12::error(XlErr::NA).into(),
+    };
+    let mut outcomes = vec![];
+    for (setter, writes) in by_setter {
+        let (rows, writes): (Vec<_>, Vec<_>) = writes.into_iter().unzip();
+        match setter.set_many(writes) {
+            Ok(o) => outcomes.extend(rows.into_iter().zip(o)),
+            Err(e) => {
+                log::error!("failure setting {} netidx paths {e}", rows.len());
+                return XLOper12::error(XlErr::NA).into();
+            }
+        }
+    }
+    outcomes.sort_by_key(|(i, _)| *i);
+    batch.outcomes(outcomes.into_iter().map(|(_, o)| o));
+    spill(&Value::from(batch.status)).into()
+}
+
+/// Like `NetSet`, but waits for the publisher to reply, and shows
+/// `OK` or its error. The write goes through an RTD topic, so it is
+/// done once per distinct path, value and type.
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetSetWait(
+    path: xll_utils::LPXLOPER12,
+    value: xll_utils::LPXLOPER12,
+    ty: xll_utils::LPXLOPER12,
+    timeout: xll_utils::LPXLOPER12,
+) -> xll_utils::LPXLOPER12 {
+    use netidx::subscriber::Value;
+    use xll_utils::*;
+    let mut res = XLOper12::error(XlErr::GettingData);
+    const CLASS_NAME: XLOper12 = xloper12_const_string!("NetidxRTD");
+    let typ = match Vec::<String>::try_from(unsafe { &*ty }) {
+        Err(_) => return XLOper12::error(XlErr::Value).into(),
+        Ok(ty) => match ty.first().map(|t| t.parse::<set_type::SetType>()) {
+            None => set_type::SetType::Auto,
+            Some(Ok(typ)) => typ,
+            Some(Err(_)) => return XLOper12::error(XlErr::NA).into(),
+        },
+    };
+    let timeout = match Value::from(unsafe { &*timeout }) {
+        Value::Null => None,
+        _ => match f64::try_from(unsafe { &*timeout }) {
+            Ok(secs) => Some(XLOper12::from(format!("timeout={}", secs))),
+            Err(()) => return XLOper12::error(XlErr::Value).into(),
+        },
+    };
+    let write = match typ.apply(Value::from(unsafe { &*value }), comglue::config().tz) {
+        Ok(v) => format!("write={}", v),
+        Err(e) => return XLOper12::error(e.into()).into(),
+    };
+    if write.encode_utf16().count() > options::MAX_TOPIC_LEN {
+        log::error!("NetSetWait value is too long for an RTD topic");
+        return XLOper12::error(XlErr::Value).into();
+    }
+    let write = XLOper12::from(write);
+    let mut args = vec![
+        CLASS_NAME.as_lpxloper12(),
+        XLOper12::missing().as_lpxloper12(),
+        path,
+        write.as_lpxloper12(),
+    ];
+    args.extend(timeout.iter().map(|x| x.as_lpxloper12()));
+    match excel12v(Xlfn::xlfRtd, res.as_mut_xloper12(), &args) {
+        0 => {
+            res.set_xlfree();
+            res.into()
+        }
+        _nonzero_ => XLOper12::error(XlErr::NA).into(),
+    }
+}
+
+/// Publish the value of a cell or a range at a netidx path for as long
+/// as the formula is in the sheet, and show it. The publication is
+/// updated when the value changes. If `writable` is TRUE, values
+/// written to the path by netidx clients replace it, until the value
+/// in the sheet changes again. An RTD topic holds the value, so it is
+/// unpublished when the formula is removed.
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetPublish(
+    path: xll_utils::LPXLOPER12,
+    value: xll_utils::LPXLOPER12,
+    ty: xll_utils::LPXLOPER12,
+    writable: xll_utils::LPXLOPER12,
+) -> xll_utils::LPXLOPER12 {
+    use netidx::{path::Path, subscriber::Value};
+    use xll_utils::*;
+    const PUBLISH: XLOper12 = xloper12_const_string!("publish=true");
+    let at = match String::try_from(unsafe { &*path }) {
+        Ok(at) => Path::from(at),
+        Err(_) => return XLOper12::error(XlErr::Value).into(),
+    };
+    let typ = match Vec::<String>::try_from(unsafe { &*ty }) {
+        Err(_) => return XLOper12::error(XlErr::Value).into(),
+        Ok(ty) => match ty.first().map(|t| t.parse::<set_type::SetType>()) {
+            None => set_type::SetType::Auto,
+            Some(Ok(typ)) => typ,
+            Some(Err(_)) => return XLOper12::error(XlErr::NA).into(),
+        },
+    };
+    let writable = match Value::from(unsafe { &*writable }) {
+        Value::Null => false,
+        _ => match bool::try_from(unsafe { &*writable }) {
+            Ok(writable) => writable,
+            Err(_) => return XLOper12::error(XlErr::Value).into(),
+        },
+    };
+    let v = match typ.apply(Value::from(unsafe { &*value }), comglue::config().tz) {
+        Ok(v) => v,
+        Err(e) => return XLOper12::error(e.into()).into(),
+    };
+    let published = connection::shared(connection::split_profile(&at).0)
+        .and_then(|conn| publish::publish(&conn, &at, v, writable));
+    if let Err(e) = published {
+        log::error!("could not publish {} {}", at, e);
+        return XLOper12::error(XlErr::NA).into();
+    }
+    match rtd_typed(path, &[PUBLISH.as_lpxloper12()]) {
+        Some(res) => spill_typed(res),
+        None => {
+            publish::release_unheld(&at);
+            XLOper12::error(XlErr::NA).into()
+        }
+    }
+}
+
+/// Publish a range as a netidx table under `base`, with the first row
+/// as the column names and `key`, or the first column, as the row
+/// names, and show the number of rows published. An RTD topic holds
+/// the table, so it is unpublished when the formula is removed.
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetPublishTable(
+    base: xll_utils::LPXLOPER12,
+    range: xll_utils::LPXLOPER12,
+    key: xll_utils::LPXLOPER12,
+) -> xll_utils::LPXLOPER12 {
+    use netidx::{path::Path, subscriber::Value};
+    use xll_utils::*;
+    let mut res = XLOper12::error(XlErr::GettingData);
+    const CLASS_NAME: XLOper12 = xloper12_const_string!("NetidxRTD");
+    const PUBLISH_TABLE: XLOper12 = xloper12_const_string!("publishtable=true");
+    let path = match String::try_from(unsafe { &*base }) {
+        Ok(path) => Path::from(path),
+        Err(_) => return XLOper12::error(XlErr::Value).into(),
+    };
+    let key = match Value::from(unsafe { &*key }) {
+        Value::Null => None,
+        _ => match String::try_from(unsafe { &*key }) {
+            Ok(key) => Some(key),
+            Err(_) => return XLOper12::error(XlErr::Value).into(),
+        },
+    };
+    let rows = match publish_table::rows(&Value::from(unsafe { &*range }), key.as_deref())
+    {
+        Ok(rows) => rows,
+        Err(e) => return XLOper12::error(e.into()).into(),
+    };
+    let n = rows.len();
+    let published = connection::shared(connection::split_profile(&path).0)
+        .and_then(|conn| publish_table::publish(&conn, &path, rows));
+    if let Err(e) = published {
+        log::error!("could not publish the table {} {}", path, e);
+        return XLOper12::error(XlErr::NA).into();
+    }
+    match excel12v(
+        Xlfn::xlfRtd,
+        res.as_mut_xloper12(),
+        &[
+            CLASS_NAME.as_lpxloper12(),
+            XLOper12::missing().as_lpxloper12(),
+            base,
+            PUBLISH_TABLE.as_lpxloper12(),
+        ],
+    ) {
+        0 => {
+            res.set_xlfree();
+            XLOper12::from(n as f64).into()
+        }
+        _nonzero_ => {
+            publish_table::release_unheld(&path);
+            XLOper12::error(XlErr::NA).into()
+        }
+    }
+}
+
+/// Read the add-in config file again, as happens on its own a few
+/// seconds after it changes. Auth changes take effect the next time the
+/// RTD server starts.
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetReloadConfig() -> i32 {
+    match comglue::reload() {
+        Ok(_) => 1,
+        Err(e) => {
+            log::error!("could not reload the config {}", e);
+            0
+        }
+    }
+}
+
+/// The procedure path and named arguments of `NetCall`
+#[cfg(windows)]
+fn call_args(
+    path: xll_utils::LPXLOPER12,
+    args: &[xll_utils::LPXLOPER12],
+) -> Result<
+    (netidx::path::Path, Vec<(String, netidx::subscriber::Value)>),
+    errors::ExcelError,
+> {
+    use netidx::subscriber::Value;
+    let path = match String::try_from(unsafe { &*path }) {
+        Ok(path) => netidx::path::Path::from(path),
+        Err(_) => return Err(errors::ExcelError::Value),
+    };
+    let args = args
+        .chunks(2)
+        .map(|p| (Value::from(unsafe { &*p[0] }), Value::from(unsafe { &*p[1] })));
+    Ok((path, rpc::args(args)?))
+}
+
+/// Call a netidx RPC procedure and show its reply. The call blocks
+/// the calculation until the procedure replies, or it times out.
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetCall(
+    path: xll_utils::LPXLOPER12,
+    name1: xll_utils::LPXLOPER12,
+    value1: xll_utils::LPXLOPER12,
+    name2: xll_utils::LPXLOPER12,
+    value2: xll_utils::LPXLOPER12,
+    name3: xll_utils::LPXLOPER12,
+    value3: xll_utils::LPXLOPER12,
+    name4: xll_utils::LPXLOPER12,
+    value4: xll_utils::LPXLOPER12,
+    name5: xll_utils::LPXLOPER12,
+    value5: xll_utils::LPXLOPER12,
+    name6: xll_utils::LPXLOPER12,
+    value6: xll_utils::LPXLOPER12,
+    name7: xll_utils::LPXLOPER12,
+    value7: xll_utils::LPXLOPER12,
+    name8: xll_utils::LPXLOPER12,
+    value8: xll_utils::LPXLOPER12,
+) -> xll_utils::LPXLOPER12 {
+    use xll_utils::*;
+    let args = [
+        name1, value1, name2, value2, name3, value3, name4, value4, name5, value5, name6,
+        value6, name7, value7, name8, value8,
+    ];
+    let (path, args) = match call_args(path, &args) {
+        Ok(call) => call,
+        Err(e) => return XLOper12::error(e.into()).into(),
+    };
+    let (profile, at) = connection::split_profile(&path);
+    match connection::shared(profile) {
+        Err(e) => {
+            log::error!("could not connect to call {}: {}", path, e);
+            XLOper12::error(XlErr::NA).into()
+        }
+        Ok(conn) => {
+            let call = rpc::call(&conn.subscriber, at, args, rpc::CALL_TIMEOUT);
+            spill(&conn.runtime.block_on(call)).into()
+        }
+    }
+}
+
+/// Like `NetCall`, but Excel carries on calculating while the call is
+/// in progress, and the reply is returned with `xlAsyncReturn`
+#[cfg(windows)]
+#[no_mangle]
+extern "system" fn NetCallAsync(
+    path: xll_utils::LPXLOPER12,
+    name1: xll_utils::LPXLOPER12,
+    value1: xll_utils::LPXLOPER12,
+    name2: xll_utils::LPXLOPER12,
+    value2: xll_utils::LPXLOPER12,
+    name3: xll_utils::LPXLOPER12,
+    value3: xll_utils::LPXLOPER12,
+    name4: xll_utils::LPXLOPER12,
+    value4: xll_utils::LPXLOPER12,
+    name5: xll_utils::LPXLOPER12,
+    value5: xll_utils::LPXLOPER12,
+    name6: xll_utils::LPXLOPER12,
+    value6: xll_utils::LPXLOPER12,
+    name7: xll_utils::LPXLOPER12,
+    value7: xll_utils::LPXLOPER12,
+    name8: xll_utils::LPXLOPER12,
+    value8: xll_utils::LPXLOPER12,
+    handle: xll_utils::LPXLOPER12,
+) {
+    use xll_utils::*;
+    let handle = unsafe { AsyncHandle::new(handle) };
+    let args = [
+        name1, value1, name2, value2, name3, value3, name4, value4, name5, value5, name6,
+        value6, name7, value7, name8, value8,
+    ];
+    let (path, args) = match call_args(path, &args) {
+        Ok(call) => call,
+        Err(e) => {
+            handle.ret(XLOper12::error(e.into()));
+            return;
+        }
+    };
+    let (profile, at) = connection::split_profile(&path);
+    match connection::shared(profile) {
+        Err(e) => {
+            log::error!("could not connect to call {}: {}", path, e);
+            handle.ret(XLOper12::error(XlErr::NA));
+        }
+        Ok(conn) => {
+            let subscriber = conn.subscriber.clone();
+            conn.runtime.spawn(async move {
+                let v = rpc::call(&subscriber, at, args, rpc::CALL_TIMEOUT).await;
+                if !handle.ret(spill(&v)) {
+                    log::warn!("could not return the reply of an async call");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(windows)]
 fn register_udfs() -> Result<()> {
     xll_udf!("NetSet", NetSet).register(
-        "QCQC$", // Q for the return value, C for the path, Q for the LPXLOPER12 value, C for the type, $ for thread-safe
-        "path,value,[type]",
+        "QCQCQ$", // Q for the return value, C for the path, Q for the LPXLOPER12 value (a range arrives as an xltypeMulti), C for the type, Q for the minimum interval, $ for thread-safe
+        "path,value,[type],[interval]",
+        "Netidx",
+        "Write a value or a range to a Netidx container",
+        &[],
+    )?;
+    xll_udf!("NetSetMany", NetSetMany).register(
+        "QQQQ$", // Q for the return value, Q for the paths, Q for the values, Q for the types, $ for thread-safe
+        "paths,values,[types]",
+        "Netidx",
+        "Write a range of values to a range of Netidx paths as one batch and show the status of each",
+        &[],
+    )?;
+    xll_udf!("NetSetWait", NetSetWait).register(
+        "QQQQQ", // Q for the return value, Q for the path, Q for the value, Q for the type, Q for the timeout
+        "path,value,[type],[timeout]",
+        "Netidx",
+        "Write a value or a range to a Netidx container and show the reply",
+        &[],
+    )?;
+    xll_udf!("NetPublish", NetPublish).register(
+        "QQQQQ", // Q for the return value, Q for the path, Q for the value, Q for the type, Q for writable
+        "path,value,[type],[writable]",
+        "Netidx",
+        "Publish a value or a range at a Netidx path for as long as the formula is in the sheet",
+        &[],
+    )?;
+    xll_udf!("NetPublishTable", NetPublishTable).register(
+        "QQQQ", // Q for the return value, Q for the base path, Q for the range, Q for the key column
+        "base,range,[key_column]",
+        "Netidx",
+        "Publish a range as a Netidx table, with the first row as the column names and a key column as the row names",
+        &[],
+    )?;
+    xll_udf!("NetCall", NetCall).register(
+        "QQQQQQQQQQQQQQQQQQ$", // Q for the return value, Q for the path, Q for each of 8 name and value pairs, $ for thread-safe
+        "path,[name1],[value1],[name2],[value2],[name3],[value3],[name4],[value4],[name5],[value5],[name6],[value6],[name7],[value7],[name8],[value8]",
+        "Netidx",
+        "Call a Netidx RPC procedure with named arguments and show its reply",
+        &[],
+    )?;
+    xll_udf!("NetCallAsync", NetCallAsync).register(
+        ">QQQQQQQQQQQQQQQQQX", // > for no return value, Q for the path, Q for each of 8 name and value pairs, X for the async handle
+        "path,[name1],[value1],[name2],[value2],[name3],[value3],[name4],[value4],[name5],[value5],[name6],[value6],[name7],[value7],[name8],[value8]",
+        "Netidx",
+        "Call a Netidx RPC procedure without blocking the calculation, and show its reply",
+        &[],
+    )?;
+    xll_udf!("NetGetArray", NetGetArray).register(
+        "QQ", // Q for the return value, Q for the path
+        "path",
+        "Netidx",
+        "Subscribe to a Netidx path and spill an array value into the sheet",
+        &[],
+    )?;
+    xll_udf!("NetTable", NetTable).register(
+        "QQQQ", // Q for the return value, Q for the base path, Q for the columns, Q for the rows
+        "base,[columns],[rows]",
+        "Netidx",
+        "Subscribe to a Netidx table and spill it into the sheet",
+        &[],
+    )?;
+    xll_udf!("NetList", NetList).register(
+        "QQQQ", // Q for the return value, Q for the path, Q for recursive, Q for the glob
+        "path,[recursive],[glob]",
+        "Netidx",
+        "List the Netidx paths under a path and spill them into the sheet",
+        &[],
+    )?;
+    xll_udf!("NetLive", NetLive).register(
+        "QQ", // Q for the return value, Q for the path
+        "path",
+        "Netidx",
+        "TRUE if the Netidx path is currently subscribed, FALSE otherwise",
+        &[],
+    )?;
+    xll_udf!("NetErrorText", NetErrorText).register(
+        "QQ", // Q for the return value, Q for the path
+        "path",
         "Netidx",
-        "Write to a Netidx container",
+        "The message of the error at a Netidx path, blank if it isn't an error",
         &[],
     )?;
+    xll_udf!("NetReloadConfig", NetReloadConfig)
+        .register_command("Netidx", "Read the add-in config file again")?;
     Ok(())
 }
 
+#[cfg(windows)]
 #[no_mangle]
 extern "system" fn xlAutoOpen() -> i32 {
     let hr = DllRegisterServer();
@@ -193,9 +831,11 @@ extern "system" fn xlAutoOpen() -> i32 {
     1 // Per Excel SDK docs, this function must return [1]
 }
 
+#[cfg(windows)]
 #[no_mangle]
 extern "system" fn xlAutoClose() -> i32 {
     1 // Per Excel SDK docs, this function must return [1]
 }
 
+#[cfg(windows)]
 register_xll_module![("NetidxRTD", CLSID, NetidxRTD),];
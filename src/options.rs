@@ -0,0 +1,403 @@
+//! Per topic options, parsed from RTD topic strings 2..N.
+//!
+//! ```text
+//! =RTD("NetidxRTD",,PATH,OPTION,OPTION,...)
+//! ```
+//!
+//! Each option is a `key=value` pair, keys are case insensitive, and
+//! empty topic strings are ignored. For compatibility a bare
+//! conflation mode such as `"max"` is also accepted.
+//!
+//! * `conflation=MODE`, see `Conflation`
+//! * `format=native|text|typed`, how to show the value
+//! * `default=VALUE`, show `VALUE` while the topic has no data
+//! * `index=N[,M...]`, show element `N` of an array, and then
+//!   element `M` of that, etc
+//! * `timeout=SECS`, show an error if no value arrives within `SECS`
+//!   seconds of connecting, not allowed with `table` or `list`
+//! * `stale=sub|keep|mark|na`, what to show when the publisher goes
+//!   away, see `Stale`
+//! * `live=true|false`, show whether the path is live instead of
+//!   its value
+//! * `table=true|false`, treat the path as the base of a table, and
+//!   show the whole table as an array of rows, see `crate::table`
+//! * `columns=A[,B...]`, the columns of a table to show, in order
+//! * `rows=X[,Y...]`, the rows of a table to show, in order
+//! * `list=true|false`, show the paths under the path instead of its
+//!   value
+//! * `recursive=true|false`, list every published path under the path
+//!   rather than just its children
+//! * `glob=PATTERN`, list the published paths matching `PATTERN`,
+//!   relative to the path unless it starts with `/`
+//! * `tz=local|utc`, the wall clock to show date times on, see `Tz`
+//! * `errortext=true|false`, show the message of an error value as
+//!   text, and nothing if the value isn't an error
+//! * `bytes=bin|hex|base64`, how to show bytes, see `Bytes`
+//! * `decimal=f64|string|decimal|currency`, how to show decimals, see
+//!   `Decimal`
+//! * `bigint=number|string`, how to show 64 bit integers that don't
+//!   fit in a double, see `BigInt`
+//! * `write=VALUE`, write `VALUE` to the path once, and show the
+//!   publisher's reply, `OK` or an error, instead of the path's value
+//! * `publish=true`, keep the value published at the path by
+//!   `=NetPublish()` published for as long as the topic is connected,
+//!   and show it
+//! * `publishtable=true`, keep the table published at the path by
+//!   `=NetPublishTable()` published for as long as the topic is
+//!   connected, and show `OK`
+use crate::{conflate::Conflation, errors::ErrorRule, serial};
+use anyhow::{bail, Error, Result};
+use base64::Engine;
+use netidx::subscriber::{Event, Value};
+use std::{fmt::Write, str::FromStr, sync::Arc, time::Duration};
+
+/// The longest topic string Excel will pass to RTD, in UTF-16 units
+#[cfg(windows)]
+pub(crate) const MAX_TOPIC_LEN: usize = 255;
+
+/// How the value of a topic is shown in the cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum Format {
+    /// convert the value to the closest native Excel type
+    #[default]
+    Native,
+    /// show the value as text, without type prefixes or quotes
+    Text,
+    /// show the value as text in netidx syntax, e.g. `i64:42`
+    Typed,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "native" => Ok(Format::Native),
+            "text" => Ok(Format::Text),
+            "typed" => Ok(Format::Typed),
+            _ => bail!("unknown format {}", s),
+        }
+    }
+}
+
+/// What to show in the cell while a topic that has had a value is
+/// unsubscribed, e.g. because the publisher is restarting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stale {
+    /// show "#SUB" (the default)
+    #[default]
+    Sub,
+    /// keep showing the last value
+    Keep,
+    /// keep showing the last value, marked as stale
+    Mark,
+    /// show #N/A
+    NA,
+}
+
+impl FromStr for Stale {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "sub" => Ok(Stale::Sub),
+            "keep" => Ok(Stale::Keep),
+            "mark" => Ok(Stale::Mark),
+            "na" => Ok(Stale::NA),
+            _ => bail!("unknown stale mode {}", s),
+        }
+    }
+}
+
+/// Date times are shown as Excel serial numbers, which have no time
+/// zone, so they are shown on the wall clock of either the local time
+/// zone or UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tz {
+    /// the local time zone of the machine (the default)
+    #[default]
+    Local,
+    Utc,
+}
+
+impl FromStr for Tz {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "local" => Ok(Tz::Local),
+            "utc" => Ok(Tz::Utc),
+            _ => bail!("unknown time zone {}", s),
+        }
+    }
+}
+
+/// How bytes are shown in a native topic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bytes {
+    /// show "#BIN" (the default)
+    #[default]
+    Bin,
+    /// lower case hex text
+    Hex,
+    /// standard base64 text
+    Base64,
+}
+
+impl FromStr for Bytes {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "bin" => Ok(Bytes::Bin),
+            "hex" => Ok(Bytes::Hex),
+            "base64" => Ok(Bytes::Base64),
+            _ => bail!("unknown bytes format {}", s),
+        }
+    }
+}
+
+/// How decimals are shown in a native topic
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decimal {
+    /// the closest double (the default)
+    #[default]
+    F64,
+    /// the exact value as text
+    String,
+    /// an exact COM decimal
+    Decimal,
+    /// COM currency, which is exact to 4 decimal places
+    Currency,
+}
+
+impl FromStr for Decimal {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "f64" => Ok(Decimal::F64),
+            "string" => Ok(Decimal::String),
+            "decimal" => Ok(Decimal::Decimal),
+            "currency" => Ok(Decimal::Currency),
+            _ => bail!("unknown decimal format {}", s),
+        }
+    }
+}
+
+/// How 64 bit integers are shown in a native topic. Excel stores
+/// numbers as doubles, which are only exact up to 2^53.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BigInt {
+    /// always a number (the default)
+    #[default]
+    Number,
+    /// text if the number is larger than 2^53
+    String,
+}
+
+impl FromStr for BigInt {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "number" => Ok(BigInt::Number),
+            "string" => Ok(BigInt::String),
+            _ => bail!("unknown bigint format {}", s),
+        }
+    }
+}
+
+/// Excel's largest exact integer
+const MAX_EXACT: u64 = 1 << 53;
+
+/// `d` as COM currency, which is an integer count of ten thousandths.
+/// None if it doesn't fit.
+pub(crate) fn currency(d: &rust_decimal::Decimal) -> Option<i64> {
+    let d = d.round_dp(4);
+    let m = d.mantissa().checked_mul(10i128.pow(4 - d.scale()))?;
+    i64::try_from(m).ok()
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TopicOptions {
+    pub(crate) conflation: Conflation,
+    pub(crate) format: Format,
+    pub(crate) default: Option<Value>,
+    pub(crate) index: Vec<usize>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) stale: Stale,
+    pub(crate) live: bool,
+    pub(crate) table: bool,
+    pub(crate) columns: Option<Vec<String>>,
+    pub(crate) rows: Option<Vec<String>>,
+    pub(crate) list: bool,
+    pub(crate) recursive: bool,
+    pub(crate) glob: Option<String>,
+    pub(crate) tz: Tz,
+    pub(crate) error_text: bool,
+    pub(crate) bytes: Bytes,
+    pub(crate) decimal: Decimal,
+    pub(crate) bigint: BigInt,
+    pub(crate) write: Option<Value>,
+    pub(crate) publish: bool,
+    pub(crate) publish_table: bool,
+    /// how errors are shown, from the config file
+    pub(crate) errors: Arc<[ErrorRule]>,
+}
+
+/// netidx syntax if it parses, otherwise a string
+fn value(val: &str) -> Value {
+    val.parse::<Value>().unwrap_or_else(|_| Value::from(String::from(val)))
+}
+
+fn names(val: &str) -> Vec<String> {
+    val.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()).map(String::from).collect()
+}
+
+impl TopicOptions {
+    pub(crate) fn parse<I, S>(topics: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let mut t = TopicOptions::default();
+        t.apply(topics)?;
+        Ok(t)
+    }
+
+    /// Override these options with the ones specified in `topics`
+    pub(crate) fn apply<I, S>(&mut self, topics: I) -> Result<()>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let t = self;
+        for topic in topics {
+            let topic = topic.as_ref().trim();
+            if topic.is_empty() {
+                continue;
+            }
+            match topic.split_once('=') {
+                None => t.conflation = topic.parse()?,
+                Some((key, val)) => {
+                    let val = val.trim();
+                    match key.trim().to_ascii_lowercase().as_str() {
+                        "conflation" => t.conflation = val.parse()?,
+                        "format" => t.format = val.parse()?,
+                        "default" => t.default = Some(value(val)),
+                        "index" => {
+                            t.index = val
+                                .split(',')
+                                .map(|i| i.trim().parse::<usize>())
+                                .collect::<Result<Vec<_>, _>>()?
+                        }
+                        "timeout" => match val.parse::<f64>()? {
+                            secs if secs > 0. => {
+                                match Duration::try_from_secs_f64(secs) {
+                                    Ok(d) => t.timeout = Some(d),
+                                    Err(_) => bail!("timeout is too long"),
+                                }
+                            }
+                            _ => bail!("timeout must be a positive number of seconds"),
+                        },
+                        "stale" => t.stale = val.parse()?,
+                        "live" => t.live = val.to_ascii_lowercase().parse()?,
+                        "table" => t.table = val.to_ascii_lowercase().parse()?,
+                        "columns" => t.columns = Some(names(val)),
+                        "rows" => t.rows = Some(names(val)),
+                        "list" => t.list = val.to_ascii_lowercase().parse()?,
+                        "recursive" => t.recursive = val.to_ascii_lowercase().parse()?,
+                        "glob" => t.glob = Some(String::from(val)),
+                        "tz" => t.tz = val.parse()?,
+                        "errortext" => t.error_text = val.to_ascii_lowercase().parse()?,
+                        "bytes" => t.bytes = val.parse()?,
+                        "decimal" => t.decimal = val.parse()?,
+                        "bigint" => t.bigint = val.parse()?,
+                        "write" => t.write = Some(value(val)),
+                        "publish" => t.publish = val.to_ascii_lowercase().parse()?,
+                        "publishtable" => {
+                            t.publish_table = val.to_ascii_lowercase().parse()?
+                        }
+                        _ => bail!("unknown topic option {}", key),
+                    }
+                }
+            }
+        }
+        if t.timeout.is_some() && (t.table || t.list) {
+            bail!("timeout is not supported on table or list topics")
+        }
+        Ok(())
+    }
+
+    /// Apply the options that act on every update before it is
+    /// conflated
+    pub(crate) fn select(&self, ev: Event) -> Event {
+        match ev {
+            Event::Unsubscribed => Event::Unsubscribed,
+            Event::Update(mut v) => {
+                for i in &self.index {
+                    v = match v {
+                        Value::Array(a) => match a.get(*i) {
+                            Some(v) => v.clone(),
+                            None => Value::Error("array index out of bounds".into()),
+                        },
+                        Value::Error(e) => Value::Error(e),
+                        _ => Value::Error("not an array".into()),
+                    }
+                }
+                Event::Update(v)
+            }
+        }
+    }
+
+    /// Render a conflated value the way it should be shown in the cell
+    pub(crate) fn format(&self, v: Value) -> Value {
+        match self.format {
+            Format::Native => self.native(v),
+            Format::Text => v.to_string_naked().into(),
+            Format::Typed => v.to_string().into(),
+        }
+    }
+
+    /// Convert the values that Excel can't show as they are. Decimals
+    /// shown as COM decimals or currency are converted by the glue.
+    fn native(&self, v: Value) -> Value {
+        let big = |n: u64| self.bigint == BigInt::String && n > MAX_EXACT;
+        match v {
+            Value::DateTime(_) | Value::Duration(_) => serial::native(v, self.tz),
+            Value::Bytes(b) => match self.bytes {
+                Bytes::Bin => Value::Bytes(b),
+                Bytes::Hex => {
+                    let mut s = String::with_capacity(b.len() * 2);
+                    for byte in b.iter() {
+                        let _ = write!(s, "{:02x}", byte);
+                    }
+                    s.into()
+                }
+                Bytes::Base64 => {
+                    base64::engine::general_purpose::STANDARD.encode(&b).into()
+                }
+            },
+            Value::Decimal(d) => match self.decimal {
+                Decimal::F64 => match f64::try_from(d) {
+                    Ok(f) => Value::F64(f),
+                    Err(_) => Value::Error("#NUM! decimal out of range".into()),
+                },
+                Decimal::String => d.to_string().into(),
+                Decimal::Decimal | Decimal::Currency => Value::Decimal(d),
+            },
+            Value::I64(n) | Value::Z64(n) if big(n.unsigned_abs()) => {
+                n.to_string().into()
+            }
+            Value::U64(n) | Value::V64(n) if big(n) => n.to_string().into(),
+            v => v,
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! `=NetPublish()` publishes the value of a cell or a range at a path.
+//! The function publishes the value itself, rather than passing it to
+//! the RTD server in a topic, which Excel limits to 255 characters, and
+//! the value stays published for as long as an RTD topic holds it. If
+//! it is writable, values written to it by netidx clients replace it,
+//! until the value in the sheet changes again. Values are kept by path
+//! including its profile prefix, if it has one.
+use crate::connection::{self, Connection};
+use anyhow::Result;
+use futures::{channel::mpsc, prelude::*};
+use fxhash::FxHashMap;
+use log::debug;
+use netidx::{
+    path::Path,
+    pool::Pooled,
+    publisher::{Publisher, Val, WriteRequest},
+    subscriber::Value,
+};
+use parking_lot::Mutex;
+use std::sync::LazyLock;
+use tokio::task::JoinHandle;
+
+/// A published value
+struct Published {
+    val: Val,
+    /// the publisher of the profile it is published in
+    publisher: Publisher,
+    /// the last value from the sheet, a write doesn't change it
+    sheet: Value,
+    /// whether subscribers may write it
+    writable: bool,
+    writes_task: JoinHandle<()>,
+}
+
+impl Drop for Published {
+    fn drop(&mut self) {
+        self.writes_task.abort()
+    }
+}
+
+/// A path that is published or held, a topic may hold it before the
+/// value is first published
+#[derive(Default)]
+struct Held {
+    published: Option<Published>,
+    /// the number of topics holding it
+    holds: usize,
+}
+
+static PUBLISHED: LazyLock<Mutex<FxHashMap<Path, Held>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// Accept the writes to `path` if it is writable, which replace the
+/// value published. Writes to a path that isn't are refused.
+async fn writes_loop(path: Path, mut writes: mpsc::Receiver<Pooled<Vec<WriteRequest>>>) {
+    while let Some(mut reqs) = writes.next().await {
+        let batch = {
+            let published = PUBLISHED.lock();
+            let p = match published.get(&path).and_then(|h| h.published.as_ref()) {
+                Some(p) => p,
+                None => break,
+            };
+            let mut batch = p.publisher.start_batch();
+            for req in reqs.drain(..) {
+                if p.writable {
+                    debug!("write to published {}", req.path);
+                    p.val.update(&mut batch, req.value)
+                } else if let Some(reply) = req.send_result {
+                    reply.send(Value::Error("read only".into()))
+                }
+            }
+            batch
+        };
+        batch.commit(None).await
+    }
+}
+
+/// Publish `v` at `path` through `conn`, the connection of the profile
+/// of `path`, or update it if it is already published and `v` isn't
+/// the value the sheet last published there. This must not be called
+/// from the runtime.
+pub(crate) fn publish(
+    conn: &Connection,
+    path: &Path,
+    v: Value,
+    writable: bool,
+) -> Result<()> {
+    let publisher = conn.publisher()?;
+    let mut published = PUBLISHED.lock();
+    let held = published.entry(path.clone()).or_default();
+    match &mut held.published {
+        Some(p) => {
+            p.writable = writable;
+            if p.sheet != v {
+                let mut batch = p.publisher.start_batch();
+                p.val.update(&mut batch, v.clone());
+                p.sheet = v;
+                drop(published);
+                conn.runtime.block_on(batch.commit(None))
+            }
+        }
+        None => {
+            let (_, at) = connection::split_profile(path);
+            let val = publisher.publish(at, v.clone())?;
+            let (tx, rx) = mpsc::channel(3);
+            publisher.writes(val.id(), tx);
+            let writes_task = conn.runtime.spawn(writes_loop(path.clone(), rx));
+            held.published =
+                Some(Published { val, publisher, sheet: v, writable, writes_task });
+        }
+    }
+    Ok(())
+}
+
+/// Keep the value at `path` published until it is released as many
+/// times as it is held
+pub(crate) fn hold(path: &Path) {
+    PUBLISHED.lock().entry(path.clone()).or_default().holds += 1;
+}
+
+/// Release a hold on the value at `path`, and unpublish it if that was
+/// the last one
+pub(crate) fn release(path: &Path) {
+    let mut published = PUBLISHED.lock();
+    if let Some(p) = published.get_mut(path) {
+        p.holds = p.holds.saturating_sub(1);
+        if p.holds == 0 {
+            published.remove(path);
+        }
+    }
+}
+
+/// Unpublish the value at `path` if no topic holds it, because the
+/// topic that was to hold it couldn't be connected
+pub(crate) fn release_unheld(path: &Path) {
+    let mut published = PUBLISHED.lock();
+    if published.get(path).map(|p| p.holds == 0).unwrap_or(false) {
+        published.remove(path);
+    }
+}
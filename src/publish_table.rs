@@ -0,0 +1,192 @@
+//! `=NetPublishTable()` publishes a range as a netidx table. The first
+//! row of the range is the column names, and the key column, the first
+//! column unless another is named, is the row names, so the cell in
+//! row `r` and column `c` is published at `base/r/c`. When the range
+//! is recalculated only the cells that changed are updated, and the
+//! cells that are no longer in it are unpublished. The table stays
+//! published for as long as an RTD topic holds it. Tables are kept by
+//! base including its profile prefix, if it has one.
+use crate::{
+    connection::{self, Connection},
+    errors::ExcelError,
+    grid::Grid,
+};
+use anyhow::Result;
+use fxhash::FxHashMap;
+use netidx::{
+    path::Path,
+    publisher::{Publisher, UpdateBatch, Val},
+    subscriber::Value,
+};
+use parking_lot::Mutex;
+use std::{collections::HashSet, sync::LazyLock};
+
+/// The rows of a table by name, each with its cells by column name
+pub(crate) type Rows = Vec<(String, Vec<(String, Value)>)>;
+
+/// The name of a row or column from a cell, blank names are None
+fn name(v: &Value) -> Result<Option<String>, ExcelError> {
+    match v {
+        Value::Null => Ok(None),
+        Value::String(s) if s.trim().is_empty() => Ok(None),
+        Value::String(s) => Ok(Some(String::from(s.trim()))),
+        Value::F64(f) if f.is_finite() => Ok(Some(f.to_string())),
+        Value::I64(i) => Ok(Some(i.to_string())),
+        _ => Err(ExcelError::Value),
+    }
+}
+
+/// Lay out `range` as a table keyed by the column named `key`, or by
+/// the first column. Columns with a blank name and rows with a blank
+/// key are left out, and a name that appears twice is `#VALUE!`, as is
+/// a key column that isn't in the header.
+pub(crate) fn rows(range: &Value, key: Option<&str>) -> Result<Rows, ExcelError> {
+    let grid = Grid::of_value(range);
+    let mut cells = grid.cells.chunks(grid.columns.max(1));
+    let header = match cells.next() {
+        Some(header) => header.iter().map(name).collect::<Result<Vec<_>, _>>()?,
+        None => return Ok(vec![]),
+    };
+    let key = match key.map(str::trim) {
+        None | Some("") => 0,
+        Some(key) => match header.iter().position(|c| c.as_deref() == Some(key)) {
+            Some(i) => i,
+            None => return Err(ExcelError::Value),
+        },
+    };
+    let mut columns = HashSet::new();
+    if !header.iter().flatten().all(|c| columns.insert(c)) {
+        return Err(ExcelError::Value);
+    }
+    let mut names = HashSet::new();
+    let mut rows = vec![];
+    for row in cells {
+        let name = match name(&row[key])? {
+            Some(name) => name,
+            None => continue,
+        };
+        if !names.insert(name.clone()) {
+            return Err(ExcelError::Value);
+        }
+        let cells = header
+            .iter()
+            .zip(row.iter())
+            .enumerate()
+            .filter_map(|(i, (c, v))| match c {
+                Some(c) if i != key => Some((c.clone(), v.clone())),
+                Some(_) | None => None,
+            })
+            .collect();
+        rows.push((name, cells))
+    }
+    Ok(rows)
+}
+
+/// A published table
+#[derive(Default)]
+struct Table {
+    /// the published cells by path, with their values
+    cells: FxHashMap<Path, (Val, Value)>,
+    /// the number of topics holding the table
+    holds: usize,
+}
+
+static TABLES: LazyLock<Mutex<FxHashMap<Path, Table>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// What a recalc of a table changes
+struct Diff {
+    batch: UpdateBatch,
+    /// the value of every cell of the table
+    values: FxHashMap<Path, Value>,
+    /// the cells that were published for the first time
+    new: FxHashMap<Path, Val>,
+}
+
+/// Compare `rows` with the table at `base`, publishing the cells that
+/// are new and queueing updates to the ones that changed. The table
+/// isn't changed, so if a cell can't be published the ones that were
+/// are unpublished when the diff is dropped.
+fn diff(publisher: &Publisher, base: &Path, rows: Rows) -> Result<Diff> {
+    let tables = TABLES.lock();
+    let cells = tables.get(base).map(|t| &t.cells);
+    let (_, at) = connection::split_profile(base);
+    let mut batch = publisher.start_batch();
+    let mut values = FxHashMap::default();
+    let mut new = FxHashMap::default();
+    for (row, row_cells) in rows {
+        let row = at.append(&Path::escape(&row));
+        for (column, v) in row_cells {
+            let path = row.append(&Path::escape(&column));
+            match cells.and_then(|cells| cells.get(&path)) {
+                Some((val, old)) => {
+                    if *old != v {
+                        val.update(&mut batch, v.clone())
+                    }
+                }
+                None => {
+                    new.insert(path.clone(), publisher.publish(path.clone(), v.clone())?);
+                }
+            }
+            values.insert(path, v);
+        }
+    }
+    Ok(Diff { batch, values, new })
+}
+
+/// Record the values of a diff that has been committed, dropping the
+/// cells that are no longer in the table unpublishes them
+fn record(base: &Path, values: FxHashMap<Path, Value>, mut new: FxHashMap<Path, Val>) {
+    let mut tables = TABLES.lock();
+    let table = tables.entry(base.clone()).or_default();
+    table.cells.retain(|path, _| values.contains_key(path));
+    for (path, v) in values {
+        match table.cells.get_mut(&path) {
+            Some((_, old)) => *old = v,
+            None => {
+                if let Some(val) = new.remove(&path) {
+                    table.cells.insert(path, (val, v));
+                }
+            }
+        }
+    }
+}
+
+/// Publish `rows` as the table at `base` through `conn`, the connection
+/// of the profile of `base`, updating the cells that changed since it
+/// was last published. This must not be called from the runtime.
+pub(crate) fn publish(conn: &Connection, base: &Path, rows: Rows) -> Result<()> {
+    let publisher = conn.publisher()?;
+    let Diff { batch, values, new } = diff(&publisher, base, rows)?;
+    conn.runtime.block_on(batch.commit(None));
+    record(base, values, new);
+    Ok(())
+}
+
+/// Keep the table at `base` published until it is released as many
+/// times as it is held
+pub(crate) fn hold(base: &Path) {
+    TABLES.lock().entry(base.clone()).or_default().holds += 1;
+}
+
+/// Release a hold on the table at `base`, and unpublish it if that
+/// was the last one
+pub(crate) fn release(base: &Path) {
+    let mut tables = TABLES.lock();
+    if let Some(table) = tables.get_mut(base) {
+        table.holds = table.holds.saturating_sub(1);
+        if table.holds == 0 {
+            tables.remove(base);
+        }
+    }
+}
+
+/// Unpublish the table at `base` if no topic holds it, because the
+/// topic that was to hold it couldn't be connected
+#[cfg(windows)]
+pub(crate) fn release_unheld(base: &Path) {
+    let mut tables = TABLES.lock();
+    if tables.get(base).map(|t| t.holds == 0).unwrap_or(false) {
+        tables.remove(base);
+    }
+}
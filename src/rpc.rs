@@ -0,0 +1,54 @@
+//! `=NetCall()` calls a netidx RPC procedure with named arguments and
+//! shows its reply. Arguments are given as alternating names and
+//! values, `=NetCall(path, name1, value1, name2, value2, ...)`.
+use crate::errors::ExcelError;
+use netidx::{
+    path::Path,
+    subscriber::{Subscriber, Value},
+};
+use netidx_protocols::rpc::client::Proc;
+use std::time::Duration;
+use tokio::time;
+
+/// How long to wait for a procedure to reply
+#[cfg(windows)]
+pub(crate) const CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The named arguments of a call from the alternating names and
+/// values of the UDF. Pairs where both are missing are the unused
+/// trailing arguments and are skipped, a value without a name is
+/// `#VALUE!`.
+pub(crate) fn args(
+    args: impl IntoIterator<Item = (Value, Value)>,
+) -> Result<Vec<(String, Value)>, ExcelError> {
+    let mut res = vec![];
+    for (name, value) in args {
+        match name {
+            Value::Null if value == Value::Null => (),
+            Value::String(name) if !name.trim().is_empty() => {
+                res.push((String::from(name.trim()), value))
+            }
+            _ => return Err(ExcelError::Value),
+        }
+    }
+    Ok(res)
+}
+
+/// Call the procedure at `path` with `args` and wait up to `timeout`
+/// for the reply, which is an error if the call failed
+pub(crate) async fn call(
+    subscriber: &Subscriber,
+    path: Path,
+    args: Vec<(String, Value)>,
+    timeout: Duration,
+) -> Value {
+    let call = async {
+        let proc = Proc::new(subscriber, path)?;
+        proc.call(args).await
+    };
+    match time::timeout(timeout, call).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(e)) => Value::Error(format!("call failed {}", e).into()),
+        Err(_) => Value::Error("timed out waiting for the procedure".into()),
+    }
+}
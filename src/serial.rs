@@ -0,0 +1,88 @@
+//! Excel represents a date and time as a serial number, the number of
+//! days since midnight on 1900-01-00 (i.e. 1899-12-31), with the time
+//! of day as the fraction. Serial numbers have no time zone, so
+//! `Tz` decides which wall clock they show.
+use crate::options::Tz;
+use anyhow::{bail, Result};
+use chrono::{offset::LocalResult, prelude::*, TimeDelta};
+use netidx::subscriber::Value;
+use std::time::Duration;
+
+const MS_PER_DAY: f64 = 86_400_000.;
+
+fn epoch() -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(1899, 12, 31)
+        .expect("never raises")
+        .and_hms_opt(0, 0, 0)
+        .expect("never raises")
+}
+
+/// Excel treats 1900 as a leap year, so every serial number from
+/// 1900-03-01 (61) onward is one more than the days since the epoch
+/// [https://learn.microsoft.com/en-us/office/troubleshoot/excel/wrongly-assumes-1900-is-leap-year]
+const LEAP_BUG: f64 = 60.;
+
+/// The serial number of `d` on the wall clock of `tz`
+pub(crate) fn of_datetime(d: DateTime<Utc>, tz: Tz) -> f64 {
+    let naive = match tz {
+        Tz::Utc => d.naive_utc(),
+        Tz::Local => d.with_timezone(&Local).naive_local(),
+    };
+    let ms = (naive - epoch()).num_milliseconds() as f64;
+    let days = ms / MS_PER_DAY;
+    if days >= LEAP_BUG {
+        days + 1.
+    } else {
+        days
+    }
+}
+
+/// The time that serial number `v` shows on the wall clock of `tz`
+pub(crate) fn to_datetime(mut v: f64, tz: Tz) -> Result<DateTime<Utc>> {
+    if !v.is_finite() || v < 0. {
+        bail!("invalid date")
+    }
+    if v >= LEAP_BUG + 1. {
+        v -= 1.
+    }
+    let naive = match TimeDelta::try_milliseconds((v * MS_PER_DAY).round() as i64)
+        .and_then(|d| epoch().checked_add_signed(d))
+    {
+        Some(naive) => naive,
+        None => bail!("date out of range"),
+    };
+    match tz {
+        Tz::Utc => Ok(naive.and_utc()),
+        Tz::Local => match Local.from_local_datetime(&naive) {
+            LocalResult::Single(d) => Ok(d.to_utc()),
+            LocalResult::Ambiguous(_, _) => bail!("ambiguous local time"),
+            LocalResult::None => bail!("nonexistent local time"),
+        },
+    }
+}
+
+/// A duration as a number of days
+pub(crate) fn of_duration(d: Duration) -> f64 {
+    d.as_secs_f64() / 86_400.
+}
+
+/// A number of days as a duration
+pub(crate) fn to_duration(v: f64) -> Result<Duration> {
+    if !v.is_finite() || v < 0. {
+        bail!("invalid duration")
+    }
+    match Duration::try_from_secs_f64(v * 86_400.) {
+        Ok(d) => Ok(d),
+        Err(_) => bail!("duration out of range"),
+    }
+}
+
+/// Replace a date time or a duration by its serial number, other
+/// values are unchanged
+pub(crate) fn native(v: Value, tz: Tz) -> Value {
+    match v {
+        Value::DateTime(d) => Value::F64(of_datetime(d, tz)),
+        Value::Duration(d) => Value::F64(of_duration(d)),
+        v => v,
+    }
+}
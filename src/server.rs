@@ -1,55 +1,373 @@
-use crate::comglue::{self, dispatch::IRTDUpdateEventWrap};
-use anyhow::Result;
+use crate::{
+    comglue,
+    conflate::{Conflated, Conflation},
+    connection::{self, Connection},
+    errors::{self, ExcelError},
+    options::{self, Format, Stale, TopicOptions},
+    publish, publish_table, setter,
+    table::Shape,
+};
+use anyhow::{bail, Result};
 use futures::{channel::mpsc, prelude::*};
 use fxhash::{FxBuildHasher, FxHashMap, FxHashSet};
-use log::{debug, error};
+use log::{debug, error, info, warn};
 use netidx::{
+    chars::Chars,
     config::Config,
     path::Path,
     pool::{Pool, Pooled},
-    subscriber::{DesiredAuth, Dval, Event, SubId, Subscriber, UpdatesFlags},
+    resolver_client::{ChangeTracker, Glob, GlobSet, ResolverRead, Table},
+    subscriber::{DesiredAuth, Dval, Event, SubId, Subscriber, UpdatesFlags, Value},
 };
 use parking_lot::Mutex;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{hash_map::Entry, HashMap, HashSet},
     default::Default,
-    fmt, mem,
-    sync::{Arc, LazyLock},
+    fmt,
+    sync::{atomic::Ordering, mpsc as std_mpsc, Arc, LazyLock},
+    time::Duration,
 };
-use tokio::runtime::Runtime;
+use tokio::{task::JoinHandle, time};
 
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
 pub(crate) struct TopicId(pub i32);
 
-static PENDING: LazyLock<Pool<FxHashMap<TopicId, Event>>> =
+/// What a topic shows in its cell after a refresh
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Cell {
+    Update(Value),
+    Unsubscribed,
+    /// the last value of a topic that is no longer live
+    Stale(Value),
+    /// Excel's #N/A error
+    NA,
+    /// an error value
+    Error(ExcelError),
+    /// a decimal as COM currency, in ten thousandths
+    Currency(i64),
+}
+
+static PENDING: LazyLock<Pool<FxHashMap<TopicId, Cell>>> =
     LazyLock::new(|| Pool::new(3, 1_000_000));
 
+/// Tell the client that there is new data waiting to be collected by
+/// `refresh_data`. In Excel this is `IRTDUpdateEvent::UpdateNotify`.
+pub(crate) trait UpdateNotify: Send + 'static {
+    fn update_notify(&self);
+
+    /// false if notifications can no longer be delivered
+    fn is_alive(&self) -> bool;
+}
+
+/// The health of the server as reported to Excel by `Heartbeat`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Health {
+    /// the runtime and subscriber were created
+    pub(crate) initialized: bool,
+    /// the runtime is running tasks
+    pub(crate) runtime: bool,
+    /// updates are being collected
+    pub(crate) updates_loop: bool,
+    /// update_notify can be delivered
+    pub(crate) notifier: bool,
+    /// the last check of the resolver succeeded
+    pub(crate) resolver: bool,
+}
+
+impl Health {
+    pub(crate) fn ok(&self) -> bool {
+        self.initialized
+            && self.runtime
+            && self.updates_loop
+            && self.notifier
+            && self.resolver
+    }
+}
+
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+#[cfg(not(test))]
+const NAMESPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+#[cfg(test)]
+const NAMESPACE_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+type ById = FxHashMap<SubId, FxHashSet<TopicId>>;
+type Updates = mpsc::Sender<Pooled<Vec<(SubId, Event)>>>;
+
+/// Send the updates of `dv` to topic `tid`
+fn watch(by_id: &mut ById, updates: &Updates, tid: TopicId, dv: &Dval) {
+    // with NO_SPURIOUS a second topic connecting to the same path
+    // won't replay the last value into the windows of the topics
+    // that are already connected to it
+    dv.updates(
+        UpdatesFlags::BEGIN_WITH_LAST | UpdatesFlags::NO_SPURIOUS,
+        updates.clone(),
+    );
+    by_id
+        .entry(dv.id())
+        .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
+        .insert(tid);
+}
+
+/// Stop sending the updates of subscription `id` to topic `tid`
+fn unwatch(by_id: &mut ById, tid: TopicId, id: SubId) {
+    if let Some(tids) = by_id.get_mut(&id) {
+        tids.remove(&tid);
+        if tids.is_empty() {
+            by_id.remove(&id);
+        }
+    }
+}
+
+/// What a topic is subscribed to
+enum Source {
+    Path(Dval),
+    /// the shape is None until it is first read from the resolver
+    Table {
+        base: Path,
+        /// the subscriber of the profile the table is in
+        subscriber: Subscriber,
+        shape: Option<Shape>,
+        cells: FxHashMap<Path, Dval>,
+        /// the last event of each cell, taken from its updates rather
+        /// than `Dval::last`, which can lag behind the first update
+        last: FxHashMap<SubId, Event>,
+    },
+    /// the paths are None until they are first read from the resolver
+    List {
+        paths: Option<Vec<Path>>,
+    },
+    /// a write, the topic shows the publisher's reply
+    Write,
+    /// holds the value published at the path by `=NetPublish()`, and
+    /// shows it
+    Publish(Path, Dval),
+    /// holds the table published at the path by `=NetPublishTable()`
+    PublishTable(Path),
+}
+
+impl Source {
+    fn ids(&self) -> Vec<SubId> {
+        match self {
+            Source::Path(dv) | Source::Publish(_, dv) => vec![dv.id()],
+            Source::Table { cells, .. } => cells.values().map(|dv| dv.id()).collect(),
+            Source::List { .. } | Source::Write | Source::PublishTable(_) => vec![],
+        }
+    }
+
+    /// Release the hold the topic has on what it publishes, if any
+    fn release(&self) {
+        match self {
+            Source::Publish(path, _) => publish::release(path),
+            Source::PublishTable(base) => publish_table::release(base),
+            Source::Path(_)
+            | Source::Table { .. }
+            | Source::List { .. }
+            | Source::Write => (),
+        }
+    }
+}
+
+/// How a topic that follows the namespace reads it from the resolver
+enum Query {
+    Table,
+    /// the children of the base
+    List,
+    /// the published paths matching the globs
+    Matching(GlobSet),
+}
+
+impl Query {
+    fn list(base: &Path, options: &TopicOptions) -> Result<Query> {
+        let glob = match (&options.glob, options.recursive) {
+            (None, false) => return Ok(Query::List),
+            (None, true) => base.append("**"),
+            (Some(g), _) if g.starts_with('/') => Path::from(g.clone()),
+            (Some(g), false) => base.append(g),
+            (Some(g), true) => base.append("**").append(g),
+        };
+        let glob = Glob::new(Chars::from(String::from(&*glob)))?;
+        Ok(Query::Matching(GlobSet::new(true, [glob])?))
+    }
+}
+
+/// Queue a refresh of topic `tid`, whose value is computed from its
+/// source when Excel calls refresh_data
+fn refresh(
+    pending: &mut FxHashMap<TopicId, Conflated>,
+    update: &Option<Box<dyn UpdateNotify>>,
+    tid: TopicId,
+) {
+    let call_update = pending.is_empty();
+    pending
+        .entry(tid)
+        .or_insert_with(|| Conflated::new(Conflation::Last, Event::Update(Value::Null)));
+    if call_update {
+        if let Some(update) = update {
+            update.update_notify()
+        }
+    }
+}
+
+struct Topic {
+    source: Source,
+    options: TopicOptions,
+    /// set when the first value arrives, used by the timeout
+    received: bool,
+    /// distinguishes this connection from earlier ones with the same id
+    seq: u64,
+    /// the last value shown, for the stale modes
+    last: Option<Value>,
+}
+
+impl Topic {
+    fn push(
+        &mut self,
+        pending: &mut FxHashMap<TopicId, Conflated>,
+        tid: TopicId,
+        ev: Event,
+    ) {
+        if let Event::Update(_) = &ev {
+            self.received = true;
+        }
+        let ev = self.options.select(ev);
+        match pending.entry(tid) {
+            Entry::Occupied(mut e) => e.get_mut().push(ev),
+            Entry::Vacant(e) => {
+                e.insert(Conflated::new(self.options.conflation, ev));
+            }
+        }
+    }
+
+    fn render(&mut self, ev: Event) -> Cell {
+        let o = &self.options;
+        match ev {
+            Event::Update(_) if o.live => Cell::Update(Value::True),
+            Event::Unsubscribed if o.live => Cell::Update(Value::False),
+            Event::Update(v) if o.error_text => Cell::Update(match v {
+                Value::Error(e) => Value::String(e),
+                _ => Value::Null,
+            }),
+            Event::Update(v) => {
+                let v = o.format(v);
+                self.last = Some(v.clone());
+                self.cell(v)
+            }
+            Event::Unsubscribed => match (o.stale, &self.last, &o.default) {
+                (Stale::Keep, Some(v), _) => self.cell(v.clone()),
+                (Stale::Mark, Some(v), _) => Cell::Stale(v.clone()),
+                (_, _, Some(v)) => self.cell(o.format(v.clone())),
+                (Stale::NA, _, None) => Cell::NA,
+                (Stale::Sub | Stale::Keep | Stale::Mark, _, None) => Cell::Unsubscribed,
+            },
+        }
+    }
+
+    /// Show a formatted value, errors are Excel errors unless the
+    /// topic asked for text
+    fn cell(&self, v: Value) -> Cell {
+        let o = &self.options;
+        if o.format != Format::Native {
+            return Cell::Update(v);
+        }
+        match v {
+            Value::Error(e) => Cell::Error(errors::classify(&o.errors, &e)),
+            Value::Decimal(d) if o.decimal == options::Decimal::Currency => {
+                match options::currency(&d) {
+                    Some(c) => Cell::Currency(c),
+                    None => Cell::Error(ExcelError::Num),
+                }
+            }
+            v => Cell::Update(v),
+        }
+    }
+}
+
 struct ServerInner {
-    runtime: Runtime,
-    update: Option<IRTDUpdateEventWrap>,
-    subscriber: Subscriber,
-    updates: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
-    by_id: FxHashMap<SubId, FxHashSet<TopicId>>,
-    by_topic: FxHashMap<TopicId, Dval>,
-    pending: Pooled<FxHashMap<TopicId, Event>>,
+    /// the connection of the default profile
+    conn: Arc<Connection>,
+    /// the connections of the named profiles the topics use
+    profiles: FxHashMap<String, Arc<Connection>>,
+    update: Option<Box<dyn UpdateNotify>>,
+    updates: Updates,
+    by_id: ById,
+    by_topic: FxHashMap<TopicId, Topic>,
+    pending: FxHashMap<TopicId, Conflated>,
+    seq: u64,
+    updates_task: JoinHandle<()>,
 }
 
 impl ServerInner {
     fn clear(&mut self) {
+        for topic in self.by_topic.values() {
+            topic.source.release()
+        }
         self.update = None;
         self.by_id.clear();
         self.by_topic.clear();
         self.pending.clear();
     }
+
+    /// The connection of `profile`, or of the default profile if it is
+    /// None, made the first time a topic uses it
+    fn connection(
+        &mut self,
+        init: &Init,
+        profile: Option<&str>,
+    ) -> Result<Arc<Connection>> {
+        let name = match profile {
+            None => return Ok(self.conn.clone()),
+            Some(name) => name,
+        };
+        if let Some(conn) = self.profiles.get(name) {
+            return Ok(conn.clone());
+        }
+        let conn = match init {
+            Init::Default => connection::shared(Some(name))?,
+            Init::Netidx(_, _, profiles) => match profiles.get(name) {
+                Some((config, auth)) => Connection::new(config.clone(), auth.clone())?,
+                None => bail!("unknown profile {}", name),
+            },
+        };
+        self.profiles.insert(String::from(name), conn.clone());
+        Ok(conn)
+    }
+
+    /// Stop the notifier, close all the subscriptions, and shut down
+    /// the connection if nothing else is using it. The server lock
+    /// must not be held, since tasks running on the runtime may be
+    /// waiting for it.
+    fn shutdown(mut self) {
+        self.clear();
+        self.updates_task.abort();
+        for (_, conn) in self.profiles.drain() {
+            connection::release(conn)
+        }
+        connection::release(self.conn)
+    }
+}
+
+/// How to connect when the server is (re)started
+enum Init {
+    /// the connection shared with `=NetSet()`, with topic settings
+    /// from the add-in config, which is read again on every start
+    Default,
+    /// a connection of its own with the specified netidx config and
+    /// auth, and the configs and auth of the named profiles
+    Netidx(Box<Config>, DesiredAuth, FxHashMap<String, (Config, DesiredAuth)>),
+}
+
+struct ServerState {
+    init: Init,
+    inner: Option<ServerInner>,
 }
 
 #[derive(Clone)]
-pub struct Server(Arc<Mutex<Option<ServerInner>>>);
+pub struct Server(Arc<Mutex<ServerState>>);
 
 impl Default for Server {
     fn default() -> Self {
-        let cfg = *comglue::CONFIG;
-        Self::new(cfg)
+        Self::new()
     }
 }
 
@@ -63,17 +381,22 @@ impl Server {
     async fn updates_loop(self, mut up: mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>) {
         debug!("updates loop started");
         while let Some(mut updates) = up.next().await {
-            let mut inner = self.0.lock();
-            if let Some(inner) = &mut *inner {
-                if let Some(update) = &mut inner.update {
-                    let call_update = inner.pending.is_empty();
+            let mut state = self.0.lock();
+            if let Some(inner) = &mut state.inner {
+                let ServerInner { update, by_id, by_topic, pending, .. } = inner;
+                if let Some(update) = update {
+                    let call_update = pending.is_empty();
                     for (id, ev) in updates.drain(..) {
-                        if let Some(tids) = inner.by_id.get(&id) {
-                            let mut iter = tids.iter();
-                            for _ in 0..tids.len() - 1 {
-                                inner.pending.insert(*iter.next().unwrap(), ev.clone());
+                        if let Some(tids) = by_id.get(&id) {
+                            for tid in tids {
+                                if let Some(topic) = by_topic.get_mut(tid) {
+                                    if let Source::Table { last, .. } = &mut topic.source
+                                    {
+                                        last.insert(id, ev.clone());
+                                    }
+                                    topic.push(pending, *tid, ev.clone())
+                                }
                             }
-                            inner.pending.insert(*iter.next().unwrap(), ev);
                         }
                     }
                     if call_update {
@@ -86,112 +409,499 @@ impl Server {
         debug!("updates loop terminated")
     }
 
-    pub(crate) fn new(cfg: comglue::Config) -> Server {
-        macro_rules! or_err {
-            ($e:expr, $msg:expr) => {
-                match $e {
-                    Ok(r) => r,
-                    Err(e) => {
-                        error!($msg, e);
-                        return Server(Arc::new(Mutex::new(None)));
-                    }
-                }
-            };
+    pub(crate) fn new() -> Server {
+        let t = Server(Arc::new(Mutex::new(ServerState {
+            init: Init::Default,
+            inner: None,
+        })));
+        if let Err(e) = t.init(&mut t.0.lock()) {
+            error!("could not init server, will retry on start {}", e);
         }
-        debug!("init runtime");
-        let runtime = or_err!(Runtime::new(), "could not init async runtime {}");
-        debug!("entering async to init subscriber");
-        let subscriber: Result<Subscriber> = runtime.block_on(async {
-            debug!("running in async context");
-            debug!("loading config file");
-            let config = Config::load_default()?;
-            let auth = match cfg.auth_mechanism {
-                None => config.default_auth(),
-                Some(comglue::Auth::Anonymous) => DesiredAuth::Anonymous,
-                Some(comglue::Auth::Kerberos) => {
-                    DesiredAuth::Krb5 { upn: None, spn: None }
-                }
-                Some(comglue::Auth::Tls) => DesiredAuth::Tls { identity: None },
-            };
-            debug!("starting subscriber");
-            Ok(Subscriber::new(config, auth)?)
-        });
-        let subscriber = or_err!(subscriber, "could not init subscriber {}");
+        t
+    }
+
+    /// Start a server that subscribes using the specified netidx
+    /// config and auth rather than the platform default config.
+    pub(crate) fn with_netidx_config(
+        config: Config,
+        auth: DesiredAuth,
+    ) -> Result<Server> {
+        let t = Server(Arc::new(Mutex::new(ServerState {
+            init: Init::Netidx(Box::new(config), auth, HashMap::default()),
+            inner: None,
+        })));
+        t.init(&mut t.0.lock())?;
+        Ok(t)
+    }
+
+    /// Add a named profile to a server started with
+    /// `with_netidx_config`, connected with the specified netidx config
+    /// and auth the first time a topic uses it
+    #[cfg(test)]
+    pub(crate) fn add_profile(&self, name: &str, config: Config, auth: DesiredAuth) {
+        if let Init::Netidx(_, _, profiles) = &mut self.0.lock().init {
+            profiles.insert(String::from(name), (config, auth));
+        }
+    }
+
+    fn init(&self, state: &mut ServerState) -> Result<()> {
+        let conn = match &state.init {
+            Init::Netidx(config, auth, _) => {
+                Connection::new((**config).clone(), auth.clone())?
+            }
+            Init::Default => connection::shared(None)?,
+        };
         debug!("init updates channel");
-        let (tx, rx) = runtime.block_on(async { mpsc::channel(3) });
-        let t = Server(Arc::new(Mutex::new(Some(ServerInner {
-            runtime,
+        let (tx, rx) = conn.runtime.block_on(async { mpsc::channel(3) });
+        debug!("starting updates loop");
+        let updates_task = conn.runtime.spawn(self.clone().updates_loop(rx));
+        state.inner = Some(ServerInner {
+            conn,
+            profiles: HashMap::default(),
             update: None,
-            subscriber,
             updates: tx,
             by_id: HashMap::with_hasher(FxBuildHasher::default()),
             by_topic: HashMap::with_hasher(FxBuildHasher::default()),
-            pending: PENDING.take(),
-        }))));
-        let t_ = t.clone();
-        if let Some(inner) = &mut *t.0.lock() {
-            debug!("starting updates loop");
-            inner.runtime.spawn(t_.updates_loop(rx));
-        }
-        t
+            pending: HashMap::with_hasher(FxBuildHasher::default()),
+            seq: 0,
+            updates_task,
+        });
+        Ok(())
     }
 
-    pub(crate) fn server_start(&self, update: IRTDUpdateEventWrap) {
-        if let Some(inner) = &mut *self.0.lock() {
+    /// Start the server, initializing it first if it was terminated,
+    /// if the last attempt to initialize it failed, or if the auth or
+    /// resolver settings of the add-in config changed since it
+    /// connected.
+    pub(crate) fn server_start<U: UpdateNotify>(&self, update: U) -> Result<()> {
+        let mut state = self.0.lock();
+        if let Init::Default = &state.init {
+            if let Err(e) = comglue::reload() {
+                warn!("could not reload config, using the old one {}", e)
+            }
+            connection::reconnect();
+            let stale = match &state.inner {
+                Some(inner) => {
+                    !connection::is_shared(&inner.conn)
+                        || !inner.profiles.values().all(connection::is_shared)
+                }
+                None => false,
+            };
+            if stale {
+                info!("the config changed, connecting again");
+                let inner = state.inner.take();
+                drop(state);
+                if let Some(inner) = inner {
+                    inner.shutdown()
+                }
+                state = self.0.lock();
+            }
+        }
+        if state.inner.is_none() {
+            self.init(&mut state)?;
+        }
+        if let Some(inner) = &mut state.inner {
             inner.clear();
-            inner.update = Some(update);
+            inner.update = Some(Box::new(update));
             debug!("server_start");
         }
+        Ok(())
     }
 
+    /// Stop the server and release everything it holds. It can be
+    /// started again with `server_start`.
     pub(crate) fn server_terminate(&self) {
-        if let Some(inner) = &mut *self.0.lock() {
-            inner.clear();
+        let (inner, shared) = {
+            let mut state = self.0.lock();
+            (state.inner.take(), matches!(state.init, Init::Default))
+        };
+        if let Some(inner) = inner {
+            inner.shutdown();
             debug!("server_terminate");
         }
+        // the shared connections are shut down too unless a formula
+        // is using one, otherwise their runtimes outlive the server
+        if shared {
+            connection::release_unused()
+        }
+    }
+
+    /// The options of a topic that doesn't specify any
+    #[cfg(windows)]
+    pub(crate) fn default_options(&self) -> TopicOptions {
+        let mut options = TopicOptions::default();
+        if let Init::Default = &self.0.lock().init {
+            let cfg = comglue::config();
+            options.stale = cfg.stale;
+            options.tz = cfg.tz;
+            options.errors = Arc::from(cfg.errors.as_slice());
+            options.bytes = cfg.bytes;
+            options.decimal = cfg.decimal;
+            options.bigint = cfg.bigint;
+        }
+        options
     }
 
-    pub(crate) fn connect_data(&self, tid: TopicId, path: Path) -> Result<()> {
+    pub(crate) fn connect_data(
+        &self,
+        tid: TopicId,
+        path: Path,
+        options: TopicOptions,
+    ) -> Result<()> {
         debug!("connect_data");
-        if let Some(inner) = &mut *self.0.lock() {
-            let dv = inner.subscriber.subscribe(path);
-            inner.pending.insert(tid, dv.last());
-            if let Some(update) = inner.update.as_ref() {
-                update.update_notify()
-            }
-            dv.updates(UpdatesFlags::BEGIN_WITH_LAST, inner.updates.clone());
-            inner
-                .by_id
-                .entry(dv.id())
-                .or_insert_with(|| HashSet::with_hasher(FxBuildHasher::default()))
-                .insert(tid);
-            inner.by_topic.insert(tid, dv);
+        // the profile prefix is kept in the path of the topic, so that
+        // the same path in different profiles is kept apart
+        let (profile, at) = connection::split_profile(&path);
+        let conn = {
+            let mut state = self.0.lock();
+            let ServerState { init, inner } = &mut *state;
+            match inner {
+                Some(inner) => inner.connection(init, profile)?,
+                None => return Ok(()),
+            }
+        };
+        if let Some(inner) = &mut self.0.lock().inner {
+            inner.seq += 1;
+            let seq = inner.seq;
+            // writes time out on their own, and publications don't wait
+            // for anything
+            if let (Some(timeout), None, false) =
+                (options.timeout, &options.write, options.publish)
+            {
+                let t = self.clone();
+                conn.runtime.spawn(async move {
+                    time::sleep(timeout).await;
+                    t.timeout(tid, seq)
+                });
+            }
+            inner.pending.remove(&tid);
+            // topics that follow the namespace are refreshed once it has
+            // been read from the resolver
+            let source = if options.table {
+                let resolver = conn.subscriber.resolver();
+                let ns = self.clone().namespace_loop(
+                    tid,
+                    seq,
+                    at.clone(),
+                    Query::Table,
+                    resolver,
+                );
+                conn.runtime.spawn(ns);
+                Source::Table {
+                    base: at,
+                    subscriber: conn.subscriber.clone(),
+                    shape: None,
+                    cells: HashMap::default(),
+                    last: HashMap::default(),
+                }
+            } else if options.list {
+                let query = Query::list(&at, &options)?;
+                let resolver = conn.subscriber.resolver();
+                conn.runtime
+                    .spawn(self.clone().namespace_loop(tid, seq, at, query, resolver));
+                Source::List { paths: None }
+            } else if let Some(v) = &options.write {
+                let dv = conn.subscriber.subscribe(at);
+                let timeout = options.timeout.unwrap_or(WRITE_TIMEOUT);
+                let write = self.clone().write(tid, seq, dv, v.clone(), timeout);
+                conn.runtime.spawn(write);
+                Source::Write
+            } else if options.publish {
+                publish::hold(&path);
+                let dv = conn.subscriber.subscribe(at);
+                watch(&mut inner.by_id, &inner.updates, tid, &dv);
+                Source::Publish(path, dv)
+            } else if options.publish_table {
+                publish_table::hold(&path);
+                Source::PublishTable(path)
+            } else {
+                let dv = conn.subscriber.subscribe(at);
+                watch(&mut inner.by_id, &inner.updates, tid, &dv);
+                setter::share(path, &dv);
+                Source::Path(dv)
+            };
+            let mut topic = Topic { source, options, received: false, seq, last: None };
+            let first = match &topic.source {
+                Source::Path(dv) | Source::Publish(_, dv) => Some(dv.last()),
+                Source::PublishTable(_) => Some(Event::Update(Value::Ok)),
+                Source::Table { .. } | Source::List { .. } | Source::Write => None,
+            };
+            if let Some(first) = first {
+                topic.push(&mut inner.pending, tid, first);
+                if let Some(update) = inner.update.as_ref() {
+                    update.update_notify()
+                }
+            }
+            inner.by_topic.insert(tid, topic);
         }
         Ok(())
     }
 
+    /// Follow the namespace under `base` for as long as the topic is
+    /// connected
+    async fn namespace_loop(
+        self,
+        tid: TopicId,
+        seq: u64,
+        base: Path,
+        query: Query,
+        resolver: ResolverRead,
+    ) {
+        let mut tracker = ChangeTracker::new(base.clone());
+        while self.is_connected(tid, seq) {
+            match resolver.check_changed(&mut tracker).await {
+                Ok(false) => (),
+                Ok(true) => {
+                    let res = match &query {
+                        Query::Table => resolver
+                            .table(base.clone())
+                            .await
+                            .map(|table| self.table_changed(tid, seq, &table)),
+                        Query::List => resolver
+                            .list(base.clone())
+                            .await
+                            .map(|paths| self.list_changed(tid, seq, paths.to_vec())),
+                        Query::Matching(globs) => {
+                            resolver.list_matching(globs).await.map(|paths| {
+                                let paths = paths.iter().flat_map(|p| p.iter().cloned());
+                                self.list_changed(tid, seq, paths.collect())
+                            })
+                        }
+                    };
+                    if let Err(e) = res {
+                        warn!("could not read the namespace under {} {}", base, e);
+                        tracker = ChangeTracker::new(base.clone());
+                    }
+                }
+                Err(e) => warn!("could not check {} for changes {}", base, e),
+            }
+            time::sleep(NAMESPACE_CHECK_INTERVAL).await
+        }
+        debug!("namespace loop for {} terminated", base)
+    }
+
+    /// Write `v` for a write topic, and show the reply
+    async fn write(self, tid: TopicId, seq: u64, dv: Dval, v: Value, timeout: Duration) {
+        let reply = setter::write_and_wait(&dv, v, timeout).await;
+        debug!("write for topic {:?} replied {}", tid, reply);
+        if let Some(inner) = &mut self.0.lock().inner {
+            let ServerInner { update, by_topic, pending, .. } = inner;
+            match by_topic.get_mut(&tid) {
+                Some(topic) if topic.seq == seq => {
+                    let call_update = pending.is_empty();
+                    topic.push(pending, tid, Event::Update(reply));
+                    if let Some(update) = update {
+                        if call_update {
+                            update.update_notify()
+                        }
+                    }
+                }
+                Some(_) | None => (),
+            }
+        }
+    }
+
+    fn is_connected(&self, tid: TopicId, seq: u64) -> bool {
+        match &self.0.lock().inner {
+            Some(inner) => {
+                inner.by_topic.get(&tid).map(|t| t.seq == seq).unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    /// Subscribe to the cells of a table topic that are new in
+    /// `table`, and unsubscribe from the ones that are gone
+    fn table_changed(&self, tid: TopicId, seq: u64, table: &Table) {
+        let mut state = self.0.lock();
+        let inner = match &mut state.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        let ServerInner { update, updates, by_id, by_topic, pending, .. } = inner;
+        let topic = match by_topic.get_mut(&tid) {
+            Some(topic) if topic.seq == seq => topic,
+            Some(_) | None => return,
+        };
+        let o = &topic.options;
+        let new = Shape::new(table, o.rows.as_deref(), o.columns.as_deref());
+        let (base, subscriber, shape, cells, last) = match &mut topic.source {
+            Source::Table { base, subscriber, shape, cells, last }
+                if shape.as_ref() != Some(&new) =>
+            {
+                (base, subscriber, shape, cells, last)
+            }
+            Source::Table { .. }
+            | Source::Path(_)
+            | Source::List { .. }
+            | Source::Write
+            | Source::Publish(..)
+            | Source::PublishTable(_) => return,
+        };
+        debug!("table {} changed shape", base);
+        let paths = new.cells(base).collect::<FxHashSet<_>>();
+        cells.retain(|path, dv| {
+            let keep = paths.contains(path);
+            if !keep {
+                unwatch(by_id, tid, dv.id());
+                last.remove(&dv.id());
+            }
+            keep
+        });
+        for path in paths {
+            if let Entry::Vacant(e) = cells.entry(path) {
+                let dv = subscriber.subscribe(e.key().clone());
+                watch(by_id, updates, tid, &dv);
+                e.insert(dv);
+            }
+        }
+        *shape = Some(new);
+        refresh(pending, update, tid)
+    }
+
+    fn list_changed(&self, tid: TopicId, seq: u64, mut new: Vec<Path>) {
+        let mut state = self.0.lock();
+        let inner = match &mut state.inner {
+            Some(inner) => inner,
+            None => return,
+        };
+        new.sort();
+        match inner.by_topic.get_mut(&tid) {
+            Some(Topic { seq: s, source: Source::List { paths }, .. })
+                if *s == seq && paths.as_ref() != Some(&new) =>
+            {
+                *paths = Some(new);
+                refresh(&mut inner.pending, &inner.update, tid)
+            }
+            Some(_) | None => (),
+        }
+    }
+
+    /// Show an error in the topic if it is still the same connection
+    /// and it still hasn't received a value
+    fn timeout(&self, tid: TopicId, seq: u64) {
+        if let Some(inner) = &mut self.0.lock().inner {
+            match inner.by_topic.get(&tid) {
+                Some(topic)
+                    if topic.seq == seq && !topic.received && !topic.options.live =>
+                {
+                    debug!("topic {:?} timed out", tid);
+                    let call_update = inner.pending.is_empty();
+                    let ev = Event::Update(Value::Error("timed out".into()));
+                    inner.pending.insert(tid, Conflated::new(Conflation::Last, ev));
+                    if let Some(update) = inner.update.as_ref() {
+                        if call_update {
+                            update.update_notify()
+                        }
+                    }
+                }
+                Some(_) | None => (),
+            }
+        }
+    }
+
     pub(crate) fn disconnect_data(&self, tid: TopicId) {
         debug!("disconnect_data");
-        if let Some(inner) = &mut *self.0.lock() {
+        if let Some(inner) = &mut self.0.lock().inner {
             inner.pending.remove(&tid);
-            if let Some(dv) = inner.by_topic.remove(&tid) {
-                if let Some(tids) = inner.by_id.get_mut(&dv.id()) {
-                    tids.remove(&tid);
-                    if tids.is_empty() {
-                        inner.by_id.remove(&dv.id());
-                    }
+            if let Some(topic) = inner.by_topic.remove(&tid) {
+                for id in topic.source.ids() {
+                    unwatch(&mut inner.by_id, tid, id)
                 }
+                topic.source.release()
             }
         }
     }
 
-    pub(crate) fn refresh_data(&self) -> Pooled<FxHashMap<TopicId, Event>> {
-        match &mut *self.0.lock() {
+    pub(crate) fn refresh_data(&self) -> Pooled<FxHashMap<TopicId, Cell>> {
+        match &mut self.0.lock().inner {
             Some(inner) => {
                 debug!("refresh_data");
-                mem::replace(&mut inner.pending, PENDING.take())
+                let mut res = PENDING.take();
+                let ServerInner { by_topic, pending, .. } = inner;
+                for (tid, c) in pending.drain() {
+                    if let Some(topic) = by_topic.get_mut(&tid) {
+                        let ev = match &topic.source {
+                            Source::Path(_)
+                            | Source::Write
+                            | Source::Publish(..)
+                            | Source::PublishTable(_) => c.finish(),
+                            Source::Table { shape: None, .. }
+                            | Source::List { paths: None } => Event::Unsubscribed,
+                            Source::List { paths: Some(paths) } => {
+                                Event::Update(Value::from(
+                                    paths
+                                        .iter()
+                                        .map(|p| Value::from(String::from(&**p)))
+                                        .collect::<Vec<_>>(),
+                                ))
+                            }
+                            Source::Table {
+                                base,
+                                shape: Some(shape),
+                                cells,
+                                last,
+                                ..
+                            } => Event::Update(shape.value(base, |path| {
+                                match cells.get(path).and_then(|dv| last.get(&dv.id())) {
+                                    Some(ev) => ev.clone(),
+                                    None => Event::Unsubscribed,
+                                }
+                            })),
+                        };
+                        res.insert(tid, topic.render(ev));
+                    }
+                }
+                res
             }
             None => Pooled::orphan(HashMap::default()),
         }
     }
+
+    /// Check the health of the server. This may block for up to a
+    /// second if the runtime is wedged.
+    pub(crate) fn heartbeat(&self) -> Health {
+        let (mut health, ping) = match &self.0.lock().inner {
+            None => return Health::default(),
+            Some(inner) => {
+                let health = Health {
+                    initialized: true,
+                    runtime: false,
+                    updates_loop: !inner.updates_task.is_finished(),
+                    notifier: inner
+                        .update
+                        .as_ref()
+                        .map(|u| u.is_alive())
+                        .unwrap_or(false),
+                    resolver: inner.conn.resolver_ok.load(Ordering::Relaxed),
+                };
+                let (tx, rx) = std_mpsc::channel();
+                inner.conn.runtime.spawn(async move {
+                    let _ = tx.send(());
+                });
+                (health, rx)
+            }
+        };
+        // wait here rather than on the runtime, whose timers won't
+        // fire if its workers are wedged. The lock must not be held,
+        // the ping may be queued behind a task that is waiting for it
+        health.runtime = ping.recv_timeout(PING_TIMEOUT).is_ok();
+        health
+    }
+
+    /// The connection the server is using, if it is running
+    #[cfg(test)]
+    pub(crate) fn connection(&self) -> Option<Arc<Connection>> {
+        self.0.lock().inner.as_ref().map(|inner| inner.conn.clone())
+    }
+
+    /// The number of distinct subscriptions and the number of topics
+    /// currently connected
+    #[cfg(test)]
+    pub(crate) fn counts(&self) -> (usize, usize) {
+        match &self.0.lock().inner {
+            Some(inner) => (inner.by_id.len(), inner.by_topic.len()),
+            None => (0, 0),
+        }
+    }
 }
@@ -0,0 +1,193 @@
+//! The `type` argument of `=NetSet()`, which converts the value of a
+//! cell to the netidx type a container expects. Conversions are exact,
+//! a value that doesn't fit the type is `#NUM!` and a value that can't
+//! be converted at all is `#VALUE!`, and in either case nothing is
+//! written.
+use crate::{errors::ExcelError, options::Tz, serial};
+use anyhow::{bail, Error, Result};
+use base64::Engine;
+use netidx::subscriber::Value;
+use std::str::FromStr;
+
+/// The type of data to write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SetType {
+    /// keep the type Excel gave the value
+    Auto,
+    I32,
+    U32,
+    Z32,
+    V32,
+    I64,
+    U64,
+    Z64,
+    V64,
+    F32,
+    F64,
+    Decimal,
+    /// a number of days
+    Duration,
+    /// an Excel serial date
+    Time,
+    /// base64 text, as netidx shows bytes
+    Bytes,
+    String,
+    Bool,
+    Null,
+}
+
+impl FromStr for SetType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "auto" => Ok(SetType::Auto),
+            "i32" => Ok(SetType::I32),
+            "u32" => Ok(SetType::U32),
+            "z32" => Ok(SetType::Z32),
+            "v32" => Ok(SetType::V32),
+            "i64" => Ok(SetType::I64),
+            "u64" => Ok(SetType::U64),
+            "z64" => Ok(SetType::Z64),
+            "v64" => Ok(SetType::V64),
+            "f32" => Ok(SetType::F32),
+            "f64" => Ok(SetType::F64),
+            "decimal" => Ok(SetType::Decimal),
+            "duration" => Ok(SetType::Duration),
+            "time" => Ok(SetType::Time),
+            "bytes" => Ok(SetType::Bytes),
+            "string" => Ok(SetType::String),
+            "bool" => Ok(SetType::Bool),
+            "null" => Ok(SetType::Null),
+            _ => bail!("unknown type {}", s),
+        }
+    }
+}
+
+/// An integer from a cell. Numbers must be whole and in range, text
+/// is parsed, which is the only way to write integers above 2^53
+/// exactly.
+fn int<T>(v: &Value) -> Result<T, ExcelError>
+where
+    T: TryFrom<i64> + TryFrom<u64> + FromStr,
+{
+    match v {
+        Value::F64(f) if !f.is_finite() || f.fract() != 0. => Err(ExcelError::Num),
+        // 2^63 and 2^64 are exact as doubles, the casts saturate so
+        // check the range first
+        Value::F64(f) if *f >= -9_223_372_036_854_775_808. && *f < 0. => {
+            T::try_from(*f as i64).map_err(|_| ExcelError::Num)
+        }
+        Value::F64(f) if *f >= 0. && *f < 18_446_744_073_709_551_616. => {
+            T::try_from(*f as u64).map_err(|_| ExcelError::Num)
+        }
+        Value::F64(_) => Err(ExcelError::Num),
+        Value::I64(i) => T::try_from(*i).map_err(|_| ExcelError::Num),
+        Value::True => T::try_from(1i64).map_err(|_| ExcelError::Num),
+        Value::False => T::try_from(0i64).map_err(|_| ExcelError::Num),
+        Value::String(s) => match s.trim().parse::<T>() {
+            Ok(i) => Ok(i),
+            // a number that doesn't fit is a range error, anything
+            // else isn't a number
+            Err(_) => match s.trim().parse::<i128>() {
+                Ok(_) => Err(ExcelError::Num),
+                Err(_) => Err(ExcelError::Value),
+            },
+        },
+        _ => Err(ExcelError::Value),
+    }
+}
+
+/// A finite double from a cell
+fn float(v: &Value) -> Result<f64, ExcelError> {
+    match v {
+        Value::F64(f) => Ok(*f),
+        Value::I64(i) => Ok(*i as f64),
+        Value::True => Ok(1.),
+        Value::False => Ok(0.),
+        Value::String(s) => match s.trim().parse::<f64>() {
+            Ok(f) if f.is_finite() => Ok(f),
+            Ok(_) => Err(ExcelError::Num),
+            Err(_) => Err(ExcelError::Value),
+        },
+        _ => Err(ExcelError::Value),
+    }
+}
+
+impl SetType {
+    /// Convert `v`, the value of a cell or a range, to this type. The
+    /// elements of a range are converted one by one. Date times are
+    /// read on the wall clock of `tz`.
+    pub(crate) fn apply(&self, v: Value, tz: Tz) -> Result<Value, ExcelError> {
+        match (self, &v) {
+            (SetType::Auto | SetType::Null, _) => (),
+            (_, Value::Error(_)) => return Err(ExcelError::Value),
+            (_, Value::Array(a)) => {
+                let a = a.iter().map(|v| self.apply(v.clone(), tz));
+                return Ok(Value::from(a.collect::<Result<Vec<_>, _>>()?));
+            }
+            (_, _) => (),
+        }
+        Ok(match self {
+            SetType::Auto => v,
+            SetType::Null => Value::Null,
+            SetType::I32 => Value::I32(int(&v)?),
+            SetType::U32 => Value::U32(int(&v)?),
+            SetType::Z32 => Value::Z32(int(&v)?),
+            SetType::V32 => Value::V32(int(&v)?),
+            SetType::I64 => Value::I64(int(&v)?),
+            SetType::U64 => Value::U64(int(&v)?),
+            SetType::Z64 => Value::Z64(int(&v)?),
+            SetType::V64 => Value::V64(int(&v)?),
+            SetType::F32 => match float(&v)? {
+                f if f.abs() <= f32::MAX as f64 => Value::F32(f as f32),
+                _ => return Err(ExcelError::Num),
+            },
+            SetType::F64 => Value::F64(float(&v)?),
+            SetType::Decimal => match &v {
+                Value::String(s) => match s.trim().parse::<rust_decimal::Decimal>() {
+                    Ok(d) => Value::Decimal(d),
+                    Err(_) => match s.trim().parse::<f64>() {
+                        Ok(_) => return Err(ExcelError::Num),
+                        Err(_) => return Err(ExcelError::Value),
+                    },
+                },
+                v => match rust_decimal::Decimal::try_from(float(v)?) {
+                    Ok(d) => Value::Decimal(d),
+                    Err(_) => return Err(ExcelError::Num),
+                },
+            },
+            SetType::Duration => match serial::to_duration(float(&v)?) {
+                Ok(d) => Value::Duration(d),
+                Err(_) => return Err(ExcelError::Num),
+            },
+            SetType::Time => match serial::to_datetime(float(&v)?, tz) {
+                Ok(d) => Value::DateTime(d),
+                Err(_) => return Err(ExcelError::Num),
+            },
+            SetType::Bytes => match &v {
+                Value::String(s) => {
+                    match base64::engine::general_purpose::STANDARD.decode(s.trim()) {
+                        Ok(b) => Value::Bytes(b.into()),
+                        Err(_) => return Err(ExcelError::Value),
+                    }
+                }
+                _ => return Err(ExcelError::Value),
+            },
+            SetType::String => match v {
+                Value::String(s) => Value::String(s),
+                Value::F64(f) => f.to_string().into(),
+                Value::I64(i) => i.to_string().into(),
+                Value::True => "true".into(),
+                Value::False => "false".into(),
+                _ => return Err(ExcelError::Value),
+            },
+            SetType::Bool => match v {
+                Value::True | Value::False => v,
+                Value::F64(f) => (f != 0.).into(),
+                Value::I64(i) => (i != 0).into(),
+                _ => return Err(ExcelError::Value),
+            },
+        })
+    }
+}
@@ -1,49 +1,387 @@
+use crate::{
+    connection::{self, Connection},
+    errors::ExcelError,
+    grid::Grid,
+    options::Tz,
+    set_type::SetType,
+};
+use anyhow::{bail, Result};
 use fxhash::FxHashMap;
 use netidx::{
-    config::Config,
     path::Path,
-    subscriber::{Dval, Subscriber, Value},
+    subscriber::{Dval, DvalWeak, Subscriber, Value},
+};
+use parking_lot::Mutex;
+use std::{
+    sync::{mpsc as std_mpsc, LazyLock},
+    time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+use tokio::{sync::mpsc, time};
+
+/// Subscriptions that haven't been written for this long are closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+/// At most this many subscriptions are kept open for writing, beyond
+/// that the least recently written is closed
+const MAX_SUBS: usize = 1000;
+const EVICT_INTERVAL: Duration = Duration::from_secs(60);
+/// How long `=NetSetMany()` waits to hear what became of its writes
+const SET_MANY_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The subscriptions of the RTD server, by path including the profile
+/// prefix, so that writes to a path that is already subscribed for
+/// reading can share the subscription instead of opening another one.
+static SHARED: LazyLock<Mutex<FxHashMap<Path, DvalWeak>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// Share the subscription to `path` with the setter. It is shared
+/// until the last reference to `dv` is dropped.
+pub(crate) fn share(path: Path, dv: &Dval) {
+    SHARED.lock().insert(path, dv.downgrade());
+}
+
+/// A subscription to `path` shared by the RTD server, if there is one
+pub(crate) fn shared(path: &Path) -> Option<Dval> {
+    let mut shared = SHARED.lock();
+    let dv = shared.get(path)?.upgrade();
+    if dv.is_none() {
+        shared.remove(path);
+    }
+    dv
+}
+
+/// A subscription the setter writes through
+struct Sub {
+    dv: Dval,
+    /// when it was last used
+    used: Instant,
+    /// the last value written, and when
+    written: Option<(Value, Instant)>,
+    /// the latest value held back by the minimum interval
+    pending: Option<Value>,
+    min_interval: Duration,
+}
+
+impl Sub {
+    fn write(&mut self, v: Value, now: Instant) {
+        self.dv.write(v.clone());
+        self.written = Some((v, now));
+        self.pending = None;
+    }
+
+    /// when the pending value may be written
+    fn due(&self) -> Option<Instant> {
+        match (&self.pending, &self.written) {
+            (None, _) => None,
+            (Some(_), None) => Some(self.used),
+            (Some(_), Some((_, at))) => Some(*at + self.min_interval),
+        }
+    }
+}
+
+/// The subscriptions the setter writes through, closed when they are
+/// idle or when there are too many, but not while they have a write
+/// held back. Writing the value that was last
+/// written to a path again does nothing unless `repeats` is set, and
+/// writes to a path with a minimum interval are held back until it
+/// has passed since the last write, then only the latest is written.
+pub(crate) struct Subs {
+    idle_timeout: Duration,
+    max: usize,
+    repeats: bool,
+    subs: FxHashMap<Path, Sub>,
+}
+
+impl Subs {
+    pub(crate) fn new(idle_timeout: Duration, max: usize, repeats: bool) -> Self {
+        Subs { idle_timeout, max: max.max(1), repeats, subs: FxHashMap::default() }
+    }
+
+    fn sub(&mut self, subscriber: &Subscriber, path: &Path, now: Instant) -> &mut Sub {
+        if !self.subs.contains_key(path) {
+            if self.subs.len() >= self.max {
+                // a subscription with a held back write is kept until
+                // it is flushed, even if that goes over the limit
+                let lru = self
+                    .subs
+                    .iter()
+                    .filter(|(_, s)| s.pending.is_none())
+                    .min_by_key(|(_, s)| s.used)
+                    .map(|(p, _)| p.clone());
+                if let Some(lru) = lru {
+                    self.subs.remove(&lru);
+                }
+            }
+            let dv = shared(path).unwrap_or_else(|| {
+                subscriber.subscribe(connection::split_profile(path).1)
+            });
+            let sub = Sub {
+                dv,
+                used: now,
+                written: None,
+                pending: None,
+                min_interval: Duration::ZERO,
+            };
+            self.subs.insert(path.clone(), sub);
+        }
+        let sub = self.subs.get_mut(path).expect("just inserted");
+        sub.used = now;
+        sub
+    }
+
+    /// The subscription to write `path` through at `now`, either one
+    /// the setter already has, one shared by the RTD server, or a new
+    /// one.
+    pub(crate) fn get(
+        &mut self,
+        subscriber: &Subscriber,
+        path: &Path,
+        now: Instant,
+    ) -> &Dval {
+        &self.sub(subscriber, path, now).dv
+    }
+
+    /// Write `v` to `path` at `now`, unless it is a repeat of the last
+    /// value written, or it is within `min_interval` of the last write,
+    /// in which case it is held back.
+    pub(crate) fn write(
+        &mut self,
+        subscriber: &Subscriber,
+        path: &Path,
+        v: Value,
+        min_interval: Duration,
+        now: Instant,
+    ) -> Outcome {
+        let repeats = self.repeats;
+        let sub = self.sub(subscriber, path, now);
+        sub.min_interval = min_interval;
+        match &sub.written {
+            Some((last, _)) if !repeats && *last == v => {
+                sub.pending = None;
+                Outcome::Skipped
+            }
+            Some((_, at)) if now < *at + min_interval => {
+                sub.pending = Some(v);
+                Outcome::Pending
+            }
+            Some(_) | None => {
+                sub.write(v, now);
+                Outcome::Written
+            }
+        }
+    }
+
+    /// When the next held back write is due, if there is one
+    pub(crate) fn next_due(&self) -> Option<Instant> {
+        self.subs.values().filter_map(|s| s.due()).min()
+    }
+
+    /// Write the held back values that are due at `now`
+    pub(crate) fn flush(&mut self, now: Instant) {
+        for sub in self.subs.values_mut() {
+            match (sub.due(), sub.pending.take()) {
+                (Some(due), Some(v)) if due <= now => sub.write(v, now),
+                (_, v) => sub.pending = v,
+            }
+        }
+    }
+
+    /// Close the subscriptions that have been idle since before
+    /// `now - idle_timeout`, and have nothing held back
+    pub(crate) fn evict(&mut self, now: Instant) {
+        let idle = self.idle_timeout;
+        self.subs.retain(|_, s| {
+            s.pending.is_some() || now.saturating_duration_since(s.used) < idle
+        });
+        SHARED.lock().retain(|_, dv| dv.upgrade().is_some());
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.subs.len()
+    }
+}
+
+/// What became of a write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Outcome {
+    Written,
+    /// it was the last value written to the path, so it wasn't
+    /// written again
+    Skipped,
+    /// it is held back until the minimum interval has passed
+    Pending,
+}
+
+impl Outcome {
+    /// The status `=NetSetMany()` shows for the write
+    pub(crate) fn status(self) -> Value {
+        match self {
+            Outcome::Written => Value::from("#SET"),
+            Outcome::Skipped => Value::from("#SKIPPED"),
+            Outcome::Pending => Value::from("#PENDING"),
+        }
+    }
+}
+
+type Write = (Path, Value, Duration);
+
+/// Writes, and where to send what became of them if anywhere
+type Writes = (Vec<Write>, Option<std_mpsc::SyncSender<Vec<Outcome>>>);
 
 pub struct Setter {
-    tx: mpsc::UnboundedSender<(Path, Value)>,
+    tx: mpsc::UnboundedSender<Writes>,
 }
 
 impl Setter {
-    pub fn new() -> anyhow::Result<Self> {
-        let cfg = Config::load_default()?;
-        let (tx, mut rx) = mpsc::unbounded_channel::<(Path, Value)>();
-        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
-        let desired_auth = cfg.default_auth();
-        let subscriber =
-            rt.block_on(async move { Subscriber::new(cfg, desired_auth) })?;
-        std::thread::Builder::new().name("netidx-setter".into()).spawn(move || {
-            // TODO: Add support for closing unused [Dval] by integrating with the RTD server. We need to marshal [Dval] ids to/from strings when a new path is subscribed or when an old path is dropped.
-            // Paths that are already subscribed and still in use won't need to be marshaled.
-            let mut subs: FxHashMap<Path, Dval> = FxHashMap::default();
-            rt.block_on(async move {
-                while let Some((path, value)) = rx.recv().await {
-                    use std::collections::hash_map::Entry::*;
-                    match subs.entry(path) {
-                        Occupied(entry) => entry.get().write(value),
-                        Vacant(vacant) => {
-                            let path = vacant.key().clone();
-                            vacant.insert(subscriber.subscribe(path)).write(value)
+    /// Write through `conn`, on its runtime. The paths written may have
+    /// the prefix of the profile `conn` is the connection of. If
+    /// `repeats` is false a value that was the last one written to its
+    /// path isn't written again.
+    pub(crate) fn new(conn: &Connection, repeats: bool) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Writes>();
+        let subscriber = conn.subscriber.clone();
+        conn.runtime.spawn(async move {
+            let mut subs = Subs::new(IDLE_TIMEOUT, MAX_SUBS, repeats);
+            let mut evict = time::interval(EVICT_INTERVAL);
+            loop {
+                let due = subs.next_due();
+                let flush = time::sleep_until(due.unwrap_or_else(Instant::now).into());
+                tokio::select! {
+                    m = rx.recv() => match m {
+                        None => break,
+                        Some((writes, reply)) => {
+                            let now = Instant::now();
+                            let outcomes = writes
+                                .into_iter()
+                                .map(|(path, value, min_interval)| {
+                                    subs.write(&subscriber, &path, value, min_interval, now)
+                                })
+                                .collect::<Vec<_>>();
+                            if let Some(reply) = reply {
+                                let _ = reply.send(outcomes);
+                            }
                         }
-                    };
+                    },
+                    _ = evict.tick() => subs.evict(Instant::now()),
+                    _ = flush, if due.is_some() => subs.flush(Instant::now()),
                 }
-            });
-            log::error!("netidx-setter thread exited");
-        })?;
-        Ok(Setter { tx })
+            }
+            log::debug!("netidx setter stopped");
+        });
+        Setter { tx }
     }
 
+    /// Write `value` to `path`, at most once every `min_interval`
     pub fn set(
         &self,
         path: Path,
         value: Value,
-    ) -> Result<(), mpsc::error::SendError<(Path, Value)>> {
-        self.tx.send((path, value))
+        min_interval: Duration,
+    ) -> Result<(), mpsc::error::SendError<Vec<Write>>> {
+        self.tx
+            .send((vec![(path, value, min_interval)], None))
+            .map_err(|mpsc::error::SendError((writes, _))| mpsc::error::SendError(writes))
+    }
+
+    /// Do all of `writes` together, so they reach the publishers as
+    /// one batch per connection, and return what became of each of
+    /// them. This must not be called from the runtime.
+    pub(crate) fn set_many(&self, writes: Vec<Write>) -> Result<Vec<Outcome>> {
+        let (tx, rx) = std_mpsc::sync_channel(1);
+        if self.tx.send((writes, Some(tx))).is_err() {
+            bail!("the setter stopped")
+        }
+        match rx.recv_timeout(SET_MANY_TIMEOUT) {
+            Ok(outcomes) => Ok(outcomes),
+            Err(_) => bail!("timed out waiting for the setter"),
+        }
+    }
+}
+
+/// The writes of `=NetSetMany()`, and the status of each of them
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Batch {
+    pub(crate) writes: Vec<(Path, Value)>,
+    /// `#SET` for each row that is to be written, the error of each
+    /// row that can't be, `#NOTSET` for the rest when any row can't
+    /// be, and nothing for blank rows
+    pub(crate) status: Vec<Value>,
+}
+
+impl Batch {
+    /// Show what became of the writes, `outcomes` being in the order
+    /// of `writes`
+    pub(crate) fn outcomes(&mut self, outcomes: impl IntoIterator<Item = Outcome>) {
+        let set = Value::from("#SET");
+        let rows = self.status.iter_mut().filter(|s| **s == set);
+        for (status, outcome) in rows.zip(outcomes) {
+            *status = outcome.status()
+        }
+    }
+}
+
+/// Pair up the cells of `paths`, `values` and `types` and convert
+/// every value to its type. There is one type for every path or one
+/// for all of them, or none at all. If any row fails nothing is
+/// written. Ranges of different sizes are `#VALUE!`.
+pub(crate) fn batch(
+    paths: &Value,
+    values: &Value,
+    types: &Value,
+    tz: Tz,
+) -> Result<Batch, ExcelError> {
+    let paths = Grid::of_value(paths).cells;
+    let values = Grid::of_value(values).cells;
+    let types = match types {
+        Value::Null => vec![],
+        types => Grid::of_value(types).cells,
+    };
+    if values.len() != paths.len() || (types.len() > 1 && types.len() != paths.len()) {
+        return Err(ExcelError::Value);
+    }
+    let typ = |i: usize| match types.get(i).or(types.first()) {
+        None | Some(Value::Null) => Ok(SetType::Auto),
+        Some(Value::String(t)) => t.parse::<SetType>().map_err(|_| ExcelError::NA),
+        Some(_) => Err(ExcelError::Value),
+    };
+    let rows = paths
+        .into_iter()
+        .zip(values)
+        .enumerate()
+        .map(|(i, row)| match row {
+            (Value::Null, Value::Null) => Ok(None),
+            (Value::String(p), v) if !p.trim().is_empty() => {
+                let v = typ(i)?.apply(v, tz)?;
+                Ok(Some((Path::from(String::from(p.trim())), v)))
+            }
+            (_, _) => Err(ExcelError::Value),
+        })
+        .collect::<Vec<_>>();
+    let failed = rows.iter().any(|r| r.is_err());
+    let status = rows
+        .iter()
+        .map(|r| match r {
+            Ok(None) => Value::Null,
+            Ok(Some(_)) if failed => Value::from("#NOTSET"),
+            Ok(Some(_)) => Value::from("#SET"),
+            Err(e) => Value::Error(e.name().into()),
+        })
+        .collect();
+    let writes = match failed {
+        true => vec![],
+        false => rows.into_iter().filter_map(|r| r.ok().flatten()).collect(),
+    };
+    Ok(Batch { writes, status })
+}
+
+/// Write `v` to `dv` and wait up to `timeout` for the publisher's
+/// reply, which is `Value::Ok` if it accepted the write, or an error
+/// with its reason if it didn't. The write is queued until `dv` is
+/// subscribed, so a path that never resolves times out.
+pub(crate) async fn write_and_wait(dv: &Dval, v: Value, timeout: Duration) -> Value {
+    match time::timeout(timeout, dv.write_with_recipt(v)).await {
+        Ok(Ok(v)) => v,
+        Ok(Err(_)) => Value::Error("the connection to the publisher was lost".into()),
+        Err(_) => Value::Error("timed out waiting for the publisher".into()),
     }
 }
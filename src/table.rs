@@ -0,0 +1,83 @@
+//! A netidx table is a base path whose children are the rows, and
+//! whose grandchildren are the cells, named by column. A table topic
+//! follows the shape of the table through the resolver, subscribes to
+//! every cell, and shows the whole table as an array of rows, with a
+//! header row of column names and the row name in the first column.
+use netidx::{
+    path::Path,
+    resolver_client::Table,
+    subscriber::{Event, Value},
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Shape {
+    pub(crate) rows: Vec<String>,
+    pub(crate) columns: Vec<String>,
+}
+
+fn name(p: &Path) -> &str {
+    Path::basename(p).unwrap_or(&**p)
+}
+
+impl Shape {
+    /// The shape to show given the table reported by the resolver.
+    /// If the rows or the columns are specified then exactly those
+    /// are shown in the specified order, otherwise they are all shown
+    /// sorted by name.
+    pub(crate) fn new(
+        table: &Table,
+        rows: Option<&[String]>,
+        columns: Option<&[String]>,
+    ) -> Shape {
+        let rows = match rows {
+            Some(rows) => rows.to_vec(),
+            None => {
+                let mut rows =
+                    table.rows.iter().map(|p| String::from(name(p))).collect::<Vec<_>>();
+                rows.sort();
+                rows
+            }
+        };
+        let columns = match columns {
+            Some(columns) => columns.to_vec(),
+            None => {
+                let mut columns = table
+                    .cols
+                    .iter()
+                    .map(|(p, _)| String::from(name(p)))
+                    .collect::<Vec<_>>();
+                columns.sort();
+                columns
+            }
+        };
+        Shape { rows, columns }
+    }
+
+    /// The path of every cell in the table
+    pub(crate) fn cells<'a>(&'a self, base: &'a Path) -> impl Iterator<Item = Path> + 'a {
+        self.rows.iter().flat_map(move |row| {
+            let row = base.append(row);
+            self.columns.iter().map(move |col| row.append(col))
+        })
+    }
+
+    /// The table as an array of rows. `cell` looks up the current
+    /// state of a cell, cells that aren't subscribed are `Null`.
+    pub(crate) fn value<F: Fn(&Path) -> Event>(&self, base: &Path, cell: F) -> Value {
+        let header = std::iter::once(Value::from(""))
+            .chain(self.columns.iter().map(|c| Value::from(c.clone())));
+        let mut rows = vec![Value::from(header.collect::<Vec<_>>())];
+        for row in &self.rows {
+            let path = base.append(row);
+            let mut cells = vec![Value::from(row.clone())];
+            for col in &self.columns {
+                cells.push(match cell(&path.append(col)) {
+                    Event::Update(v) => v,
+                    Event::Unsubscribed => Value::Null,
+                })
+            }
+            rows.push(Value::from(cells));
+        }
+        Value::from(rows)
+    }
+}
@@ -0,0 +1,1939 @@
+//! A headless harness for the RTD server. It runs an in process
+//! resolver server and publisher, and plays the part of Excel by
+//! calling `server_start`, `connect_data`, `refresh_data` and
+//! `disconnect_data` on a `Server`, keeping the latest value of every
+//! topic in a simulated sheet.
+use crate::{
+    conflate::{Conflated, Conflation},
+    errors::{ErrorRule, ExcelError},
+    options::{Format, Stale, TopicOptions, Tz},
+    server::{Cell, Server, TopicId, UpdateNotify},
+};
+use futures::prelude::*;
+use fxhash::FxHashMap;
+use netidx::{
+    config::{self, Config},
+    path::Path,
+    publisher::{BindCfg, Publisher, Val},
+    resolver_server::{config::Config as ResolverConfig, Server as Resolver},
+    subscriber::{DesiredAuth, Event, Value},
+};
+use netidx_protocols::rpc::server::{ArgSpec, Proc, RpcCall};
+use parking_lot::Mutex;
+use std::{
+    mem::ManuallyDrop,
+    sync::{mpsc, Arc, Weak},
+    time::{Duration, Instant},
+};
+use tokio::runtime::Runtime;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+const RESOLVER: &str = r#"{
+    "member_servers": [{
+        "addr": "127.0.0.1:0",
+        "bind_addr": "127.0.0.1",
+        "auth": "Anonymous",
+        "id_map_type": "DoNotMap"
+    }]
+}"#;
+
+/// Excel's update notifier, it is alive for as long as the harness
+/// holds the matching receiver
+struct TestNotify(mpsc::Sender<()>, Weak<()>);
+
+impl UpdateNotify for TestNotify {
+    fn update_notify(&self) {
+        let _ = self.0.send(());
+    }
+
+    fn is_alive(&self) -> bool {
+        self.1.strong_count() > 0
+    }
+}
+
+pub(crate) struct Harness {
+    runtime: Runtime,
+    resolver: Option<Resolver>,
+    pub(crate) config: Config,
+    publisher: ManuallyDrop<Publisher>,
+    published: FxHashMap<Path, Val>,
+    procs: Vec<Proc>,
+    pub(crate) server: Server,
+    notify: Option<(mpsc::Receiver<()>, Arc<()>)>,
+    pub(crate) sheet: FxHashMap<TopicId, Cell>,
+}
+
+impl Harness {
+    pub(crate) fn new() -> Self {
+        let runtime = Runtime::new().expect("runtime");
+        let (resolver, config, publisher) = runtime.block_on(async {
+            let resolver = Resolver::new(
+                ResolverConfig::parse(RESOLVER).expect("resolver config"),
+                false,
+                0,
+            )
+            .await
+            .expect("start resolver");
+            let config = Config::from_file(config::file::Config {
+                base: "/".into(),
+                addrs: vec![(*resolver.local_addr(), config::file::Auth::Anonymous)],
+                tls: None,
+                default_auth: config::DefaultAuthMech::Anonymous,
+                default_bind_config: None,
+            })
+            .expect("client config");
+            let publisher = Publisher::new(
+                config.clone(),
+                DesiredAuth::Anonymous,
+                BindCfg::Local,
+                768,
+                3,
+            )
+            .await
+            .expect("start publisher");
+            (resolver, config, publisher)
+        });
+        let server = Server::with_netidx_config(config.clone(), DesiredAuth::Anonymous)
+            .expect("start server");
+        Harness {
+            runtime,
+            resolver: Some(resolver),
+            config,
+            publisher: ManuallyDrop::new(publisher),
+            published: FxHashMap::default(),
+            procs: vec![],
+            server,
+            notify: None,
+            sheet: FxHashMap::default(),
+        }
+    }
+
+    /// publish `path` with the initial value `v`, or update it if it
+    /// is already published
+    pub(crate) fn publish(&mut self, path: &str, v: Value) {
+        let path = Path::from(String::from(path));
+        match self.published.get(&path) {
+            Some(val) => {
+                let mut batch = self.publisher.start_batch();
+                val.update(&mut batch, v);
+                self.runtime.block_on(batch.commit(None))
+            }
+            None => {
+                let val = self.publisher.publish(path.clone(), v).expect("publish");
+                self.published.insert(path, val);
+                self.runtime.block_on(self.publisher.flushed())
+            }
+        }
+    }
+
+    /// publish an RPC procedure at `path` taking the named `args`,
+    /// which replies with `reply` of the arguments it was called with
+    pub(crate) fn publish_proc<F>(&mut self, path: &str, args: &[&str], reply: F)
+    where
+        F: Fn(Vec<(String, Value)>) -> Value + Send + 'static,
+    {
+        let _rt = self.runtime.enter();
+        let args = args.iter().map(|name| ArgSpec {
+            name: (*name).into(),
+            doc: Value::Null,
+            default_value: Value::Null,
+        });
+        let proc = Proc::new(
+            &self.publisher,
+            Path::from(String::from(path)),
+            Value::Null,
+            args,
+            move |mut c: RpcCall| -> Option<()> {
+                let mut args = c
+                    .args
+                    .drain()
+                    .map(|(name, v)| (String::from(&*name), v))
+                    .collect::<Vec<_>>();
+                args.sort_by(|(a, _), (b, _)| a.cmp(b));
+                c.reply.send(reply(args));
+                None
+            },
+            None,
+        )
+        .expect("publish procedure");
+        self.procs.push(proc);
+        self.runtime.block_on(self.publisher.flushed())
+    }
+
+    /// accept writes to the published `path`, replying with `reply`
+    /// of the value written, and return a log of the values written
+    pub(crate) fn accept_writes<F>(
+        &mut self,
+        path: &str,
+        reply: F,
+    ) -> Arc<Mutex<Vec<Value>>>
+    where
+        F: Fn(&Value) -> Value + Send + 'static,
+    {
+        let val = self.published.get(path).expect("published");
+        let (tx, mut rx) = futures::channel::mpsc::channel(10);
+        self.publisher.writes(val.id(), tx);
+        let written = Arc::new(Mutex::new(vec![]));
+        let log = written.clone();
+        self.runtime.spawn(async move {
+            while let Some(mut batch) = rx.next().await {
+                for req in batch.drain(..) {
+                    log.lock().push(req.value.clone());
+                    if let Some(res) = req.send_result {
+                        res.send(reply(&req.value))
+                    }
+                }
+            }
+        });
+        written
+    }
+
+    /// stop publishing `path`
+    pub(crate) fn unpublish(&mut self, path: &str) {
+        self.published.remove(path);
+        self.runtime.block_on(self.publisher.flushed())
+    }
+
+    pub(crate) fn server_start(&mut self) {
+        let (tx, rx) = mpsc::channel();
+        let alive = Arc::new(());
+        self.server
+            .server_start(TestNotify(tx, Arc::downgrade(&alive)))
+            .expect("server start");
+        self.notify = Some((rx, alive));
+        self.sheet.clear();
+    }
+
+    pub(crate) fn server_terminate(&mut self) {
+        self.server.server_terminate();
+        self.notify = None;
+    }
+
+    /// simulate the death of the update notify thread
+    pub(crate) fn kill_notifier(&mut self) {
+        self.notify = None;
+    }
+
+    /// shut down the resolver server
+    pub(crate) fn stop_resolver(&mut self) {
+        self.resolver = None;
+    }
+
+    pub(crate) fn connect_data(&mut self, tid: i32, path: &str) {
+        self.connect_data_with(tid, path, &[])
+    }
+
+    /// connect with extra topic strings, as in
+    /// `=RTD("NetidxRTD",,path,options[0],options[1],...)`
+    pub(crate) fn connect_data_with(&mut self, tid: i32, path: &str, options: &[&str]) {
+        let options = TopicOptions::parse(options).expect("valid options");
+        self.server
+            .connect_data(TopicId(tid), Path::from(String::from(path)), options)
+            .expect("connect data")
+    }
+
+    pub(crate) fn disconnect_data(&mut self, tid: i32) {
+        self.server.disconnect_data(TopicId(tid));
+        self.sheet.remove(&TopicId(tid));
+    }
+
+    /// call refresh_data and apply the result to the sheet, returning
+    /// the topics that were updated
+    pub(crate) fn refresh_data(&mut self) -> FxHashMap<TopicId, Cell> {
+        let mut updates = self.server.refresh_data();
+        let res = updates.drain().collect::<FxHashMap<_, _>>();
+        for (tid, ev) in res.iter() {
+            self.sheet.insert(*tid, ev.clone());
+        }
+        res
+    }
+
+    /// wait for update_notify and then refresh, as Excel would,
+    /// until `f` is true of the sheet. Panic if that doesn't happen
+    /// within the timeout.
+    pub(crate) fn wait_until<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&FxHashMap<TopicId, Cell>) -> bool,
+    {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            self.refresh_data();
+            if f(&self.sheet) {
+                break;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                panic!("timed out waiting for the sheet, {:?}", self.sheet)
+            }
+            let (notify, _) = self.notify.as_ref().expect("server not started");
+            match notify.recv_timeout(deadline - now) {
+                Ok(()) => while let Ok(()) = notify.try_recv() {},
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    panic!("update notify disconnected")
+                }
+            }
+        }
+    }
+
+    /// wait until topic `tid` shows `ev`
+    pub(crate) fn wait_for(&mut self, tid: i32, ev: Cell) {
+        self.wait_until(|sheet| sheet.get(&TopicId(tid)) == Some(&ev))
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        // the publisher clears its paths from the resolver on the
+        // runtime when it is dropped
+        self.server.server_terminate();
+        let _rt = self.runtime.enter();
+        self.procs.clear();
+        self.published.clear();
+        unsafe { ManuallyDrop::drop(&mut self.publisher) }
+    }
+}
+
+mod server {
+    use super::*;
+
+    #[test]
+    fn connect_refresh_disconnect() {
+        let mut h = Harness::new();
+        h.publish("/test/foo", Value::I64(42));
+        h.server_start();
+        h.connect_data(0, "/test/foo");
+        h.wait_for(0, Cell::Update(Value::I64(42)));
+        h.publish("/test/foo", Value::I64(43));
+        h.wait_for(0, Cell::Update(Value::I64(43)));
+        h.disconnect_data(0);
+        assert_eq!(h.server.counts(), (0, 0));
+        h.publish("/test/bar", Value::I64(0));
+        h.connect_data(1, "/test/bar");
+        h.wait_for(1, Cell::Update(Value::I64(0)));
+        h.publish("/test/foo", Value::I64(44));
+        h.publish("/test/bar", Value::I64(1));
+        h.wait_for(1, Cell::Update(Value::I64(1)));
+        assert!(!h.sheet.contains_key(&TopicId(0)));
+    }
+
+    #[test]
+    fn shared_subscription() {
+        let mut h = Harness::new();
+        h.publish("/test/foo", Value::F64(1.));
+        h.server_start();
+        h.connect_data(0, "/test/foo");
+        h.connect_data(1, "/test/foo");
+        assert_eq!(h.server.counts(), (1, 2));
+        h.publish("/test/foo", Value::F64(2.));
+        h.wait_until(|sheet| {
+            sheet.get(&TopicId(0)) == Some(&Cell::Update(Value::F64(2.)))
+                && sheet.get(&TopicId(1)) == Some(&Cell::Update(Value::F64(2.)))
+        });
+        h.disconnect_data(0);
+        assert_eq!(h.server.counts(), (1, 1));
+        h.publish("/test/foo", Value::F64(3.));
+        h.wait_for(1, Cell::Update(Value::F64(3.)));
+        assert!(!h.sheet.contains_key(&TopicId(0)));
+        h.disconnect_data(1);
+        assert_eq!(h.server.counts(), (0, 0));
+    }
+
+    #[test]
+    fn unpublished() {
+        let mut h = Harness::new();
+        h.publish("/test/foo", Value::from("hello"));
+        h.server_start();
+        h.connect_data(0, "/test/foo");
+        h.wait_for(0, Cell::Update(Value::from("hello")));
+        h.unpublish("/test/foo");
+        h.wait_for(0, Cell::Unsubscribed);
+        h.publish("/test/foo", Value::from("world"));
+        h.wait_for(0, Cell::Update(Value::from("world")));
+    }
+
+    #[test]
+    fn terminate_and_restart() {
+        let mut h = Harness::new();
+        h.publish("/test/foo", Value::I64(1));
+        h.publish("/test/bar", Value::I64(2));
+        h.server_start();
+        h.connect_data(0, "/test/foo");
+        h.wait_for(0, Cell::Update(Value::I64(1)));
+        h.server_terminate();
+        assert_eq!(h.server.counts(), (0, 0));
+        assert!(h.refresh_data().is_empty());
+        // everything was released, including the runtime
+        assert!(!h.server.heartbeat().initialized);
+        h.server_terminate();
+        h.server_start();
+        assert!(h.server.heartbeat().ok());
+        h.connect_data(0, "/test/bar");
+        h.wait_for(0, Cell::Update(Value::I64(2)));
+        h.publish("/test/foo", Value::I64(3));
+        h.publish("/test/bar", Value::I64(4));
+        h.wait_for(0, Cell::Update(Value::I64(4)));
+    }
+
+    #[test]
+    fn terminate_releases_shared() {
+        use crate::{comglue, connection};
+        let mut h = Harness::new();
+        h.publish("/test/shared", Value::I64(1));
+        let cfg = comglue::Config {
+            resolvers: vec![comglue::Resolver {
+                addr: h.config.addrs[0].0,
+                auth: comglue::ResolverAuth::Anonymous,
+            }],
+            ..comglue::Config::default()
+        };
+        let dir = comglue::config_dir().unwrap();
+        std::fs::write(dir.join("config.json"), serde_json::to_string(&cfg).unwrap())
+            .unwrap();
+        h.server_terminate();
+        h.server = Server::new();
+        h.server_start();
+        h.connect_data(0, "/test/shared");
+        h.wait_for(0, Cell::Update(Value::I64(1)));
+        let conn = {
+            let conn = h.server.connection().unwrap();
+            assert!(connection::is_shared(&conn));
+            Arc::downgrade(&conn)
+        };
+        h.server_terminate();
+        assert!(conn.upgrade().is_none());
+        h.server_start();
+        h.connect_data(0, "/test/shared");
+        h.wait_for(0, Cell::Update(Value::I64(1)));
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
+
+mod conflate {
+    use super::*;
+
+    fn window(mode: Conflation, updates: &[Value]) -> Event {
+        let mut iter = updates.iter().cloned().map(Event::Update);
+        let mut c = Conflated::new(mode, iter.next().unwrap());
+        for ev in iter {
+            c.push(ev)
+        }
+        c.finish()
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("".parse::<Conflation>().unwrap(), Conflation::Last);
+        assert_eq!("MAX".parse::<Conflation>().unwrap(), Conflation::Max);
+        assert_eq!(" vwap ".parse::<Conflation>().unwrap(), Conflation::Vwap);
+        assert!("median".parse::<Conflation>().is_err());
+    }
+
+    #[test]
+    fn modes() {
+        let updates = [Value::F64(3.), Value::F64(7.), Value::F64(-2.), Value::F64(5.)];
+        let w = |mode| window(mode, &updates);
+        assert_eq!(w(Conflation::Last), Event::Update(Value::F64(5.)));
+        assert_eq!(w(Conflation::First), Event::Update(Value::F64(3.)));
+        assert_eq!(w(Conflation::Max), Event::Update(Value::F64(7.)));
+        assert_eq!(w(Conflation::Min), Event::Update(Value::F64(-2.)));
+        assert_eq!(w(Conflation::Sum), Event::Update(Value::F64(13.)));
+        assert_eq!(w(Conflation::Count), Event::Update(Value::I64(4)));
+    }
+
+    #[test]
+    fn vwap() {
+        let pv = |p: f64, v: f64| Value::from(vec![p, v]);
+        assert_eq!(
+            window(Conflation::Vwap, &[pv(10., 1.), pv(20., 3.)]),
+            Event::Update(Value::F64(17.5))
+        );
+        match window(Conflation::Vwap, &[pv(10., 1.), Value::F64(20.)]) {
+            Event::Update(Value::Error(_)) => (),
+            ev => panic!("expected an error, got {:?}", ev),
+        }
+        match window(Conflation::Vwap, &[pv(10., 0.)]) {
+            Event::Update(Value::Error(_)) => (),
+            ev => panic!("expected an error, got {:?}", ev),
+        }
+    }
+
+    #[test]
+    fn sum_overflow() {
+        let is_err = |ev| matches!(ev, Event::Update(Value::Error(_)));
+        assert!(is_err(window(Conflation::Sum, &[Value::I64(i64::MAX), Value::I64(1)])));
+        assert!(is_err(window(Conflation::Sum, &[Value::U32(u32::MAX), Value::V32(1)])));
+        assert!(is_err(window(Conflation::Sum, &[Value::U64(u64::MAX), Value::I32(1)])));
+        let max = rust_decimal::Decimal::MAX;
+        assert!(is_err(window(
+            Conflation::Sum,
+            &[Value::Decimal(max), Value::Decimal(max)]
+        )));
+        assert!(is_err(window(Conflation::Sum, &[Value::Decimal(max), Value::I64(1)])));
+        assert!(is_err(window(Conflation::Sum, &[Value::F64(1.), Value::Decimal(max)])));
+        assert!(is_err(window(Conflation::Sum, &[Value::Decimal(max), Value::True])));
+        let long = Value::Duration(Duration::MAX);
+        assert!(is_err(window(Conflation::Sum, &[long.clone(), long.clone()])));
+        let now = Value::DateTime(chrono::Utc::now());
+        assert!(is_err(window(Conflation::Sum, &[now.clone(), long.clone()])));
+        assert!(is_err(window(Conflation::Sum, &[long, now])));
+        let day = Value::Duration(Duration::from_secs(86_400));
+        assert_eq!(
+            window(Conflation::Sum, &[day.clone(), day]),
+            Event::Update(Value::Duration(Duration::from_secs(2 * 86_400)))
+        );
+        assert_eq!(
+            window(Conflation::Sum, &[Value::U32(2), Value::I64(-3), Value::U64(4)]),
+            Event::Update(Value::I64(3))
+        );
+        let d = Value::Array(Arc::from([Value::Decimal(max), Value::I64(1)]));
+        assert_eq!(
+            window(Conflation::Sum, &[d, Value::from("[1, 2]")]),
+            Event::Update(Value::Array(Arc::from([
+                Value::Error("sum overflowed".into()),
+                Value::I64(3)
+            ])))
+        );
+    }
+
+    #[test]
+    fn unsubscribed_resets() {
+        let mut c = Conflated::new(Conflation::Sum, Event::Update(Value::I64(1)));
+        c.push(Event::Update(Value::I64(2)));
+        c.push(Event::Unsubscribed);
+        assert_eq!(c.finish(), Event::Unsubscribed);
+        let mut c = Conflated::new(Conflation::Count, Event::Unsubscribed);
+        c.push(Event::Update(Value::I64(2)));
+        c.push(Event::Update(Value::I64(2)));
+        assert_eq!(c.finish(), Event::Update(Value::I64(2)));
+    }
+
+    #[test]
+    fn max_through_server() {
+        let mut h = Harness::new();
+        h.publish("/test/foo", Value::F64(1.));
+        h.server_start();
+        h.connect_data_with(0, "/test/foo", &["max"]);
+        h.connect_data_with(1, "/test/foo", &["conflation=last"]);
+        h.wait_for(0, Cell::Update(Value::F64(1.)));
+        for v in [5., 3., 2.] {
+            h.publish("/test/foo", Value::F64(v));
+        }
+        // the window that contains 5 must show 5 in the max topic
+        let mut seen_max = false;
+        h.wait_until(|sheet| {
+            seen_max |= sheet.get(&TopicId(0)) == Some(&Cell::Update(Value::F64(5.)));
+            seen_max && sheet.get(&TopicId(1)) == Some(&Cell::Update(Value::F64(2.)))
+        });
+    }
+}
+
+mod options {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let t = TopicOptions::parse(&[] as &[&str]).unwrap();
+        assert_eq!(t, TopicOptions::default());
+        let t = TopicOptions::parse([
+            "Conflation=Sum",
+            "",
+            "format=text",
+            "default=42",
+            "index=1, 0",
+            "timeout=1.5",
+        ])
+        .unwrap();
+        assert_eq!(t.conflation, Conflation::Sum);
+        assert_eq!(t.format, Format::Text);
+        assert_eq!(t.default, Some(Value::I64(42)));
+        assert_eq!(t.index, vec![1, 0]);
+        assert_eq!(t.timeout, Some(Duration::from_millis(1500)));
+        let t = TopicOptions::parse(["count", "default=no data"]).unwrap();
+        assert_eq!(t.conflation, Conflation::Count);
+        assert_eq!(t.default, Some(Value::from("no data")));
+        let t = TopicOptions::parse(["stale=NA", "live=TRUE"]).unwrap();
+        assert_eq!(t.stale, Stale::NA);
+        assert!(t.live);
+        assert_eq!(t.tz, Tz::Local);
+        assert_eq!(TopicOptions::parse(["tz=UTC"]).unwrap().tz, Tz::Utc);
+        assert!(TopicOptions::parse(["tz=mars"]).is_err());
+        assert!(TopicOptions::parse(["stale=old"]).is_err());
+        assert!(TopicOptions::parse(["colour=red"]).is_err());
+        assert!(TopicOptions::parse(["index=a"]).is_err());
+        assert!(TopicOptions::parse(["timeout=0"]).is_err());
+        assert!(TopicOptions::parse(["timeout=nan"]).is_err());
+        assert!(TopicOptions::parse(["timeout=inf"]).is_err());
+        assert!(TopicOptions::parse(["timeout=1e20"]).is_err());
+        assert!(TopicOptions::parse(["table=true", "timeout=5"]).is_err());
+        assert!(TopicOptions::parse(["timeout=5", "list=true"]).is_err());
+        assert!(TopicOptions::parse(["format=xml"]).is_err());
+    }
+
+    #[test]
+    fn select_and_format() {
+        let a = Value::from(vec![Value::I64(1), Value::from(vec![Value::I64(2)])]);
+        let t = TopicOptions::parse(["index=1,0"]).unwrap();
+        assert_eq!(t.select(Event::Update(a.clone())), Event::Update(Value::I64(2)));
+        let t = TopicOptions::parse(["index=2"]).unwrap();
+        assert!(matches!(
+            t.select(Event::Update(a.clone())),
+            Event::Update(Value::Error(_))
+        ));
+        let t = TopicOptions::parse(["index=0,0"]).unwrap();
+        assert!(matches!(t.select(Event::Update(a)), Event::Update(Value::Error(_))));
+        let t = TopicOptions::parse(["format=typed"]).unwrap();
+        assert_eq!(t.format(Value::I64(42)), Value::from("i64:42"));
+        let t = TopicOptions::parse(["format=text"]).unwrap();
+        assert_eq!(t.format(Value::I64(42)), Value::from("42"));
+    }
+
+    #[test]
+    fn through_server() {
+        let mut h = Harness::new();
+        h.publish("/test/foo", Value::from(vec![Value::F64(1.), Value::F64(2.)]));
+        h.server_start();
+        h.connect_data_with(0, "/test/foo", &["index=1", "format=text"]);
+        h.connect_data_with(1, "/test/bar", &["default=none"]);
+        h.connect_data_with(2, "/test/baz", &["timeout=0.1"]);
+        h.wait_for(0, Cell::Update("2".into()));
+        h.wait_for(1, Cell::Update("none".into()));
+        h.wait_for(2, Cell::Error(ExcelError::Value));
+    }
+}
+
+mod stale {
+    use super::*;
+
+    #[test]
+    fn modes() {
+        let mut h = Harness::new();
+        h.publish("/test/foo", Value::I64(42));
+        h.server_start();
+        h.connect_data(0, "/test/foo");
+        h.connect_data_with(1, "/test/foo", &["stale=keep"]);
+        h.connect_data_with(2, "/test/foo", &["stale=mark"]);
+        h.connect_data_with(3, "/test/foo", &["stale=na"]);
+        h.connect_data_with(4, "/test/foo", &["stale=keep", "default=0"]);
+        h.connect_data_with(5, "/test/foo", &["live=true"]);
+        h.wait_until(|sheet| {
+            (0..5).all(|i| sheet.get(&TopicId(i)) == Some(&Cell::Update(Value::I64(42))))
+                && sheet.get(&TopicId(5)) == Some(&Cell::Update(Value::True))
+        });
+        h.unpublish("/test/foo");
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Unsubscribed)
+                && get(1) == Some(&Cell::Update(Value::I64(42)))
+                && get(2) == Some(&Cell::Stale(Value::I64(42)))
+                && get(3) == Some(&Cell::NA)
+                && get(4) == Some(&Cell::Update(Value::I64(42)))
+                && get(5) == Some(&Cell::Update(Value::False))
+        });
+        h.publish("/test/foo", Value::I64(43));
+        h.wait_until(|sheet| {
+            (0..5).all(|i| sheet.get(&TopicId(i)) == Some(&Cell::Update(Value::I64(43))))
+                && sheet.get(&TopicId(5)) == Some(&Cell::Update(Value::True))
+        });
+    }
+
+    #[test]
+    fn never_subscribed() {
+        let mut h = Harness::new();
+        h.server_start();
+        h.connect_data_with(0, "/test/foo", &["stale=keep"]);
+        h.connect_data_with(1, "/test/foo", &["stale=na"]);
+        h.connect_data_with(2, "/test/foo", &["stale=mark", "default=0"]);
+        h.wait_until(|sheet| {
+            sheet.get(&TopicId(0)) == Some(&Cell::Unsubscribed)
+                && sheet.get(&TopicId(1)) == Some(&Cell::NA)
+                && sheet.get(&TopicId(2)) == Some(&Cell::Update(Value::I64(0)))
+        });
+    }
+}
+
+mod heartbeat {
+    use super::*;
+
+    #[test]
+    fn healthy() {
+        let mut h = Harness::new();
+        assert!(!h.server.heartbeat().notifier);
+        h.server_start();
+        let health = h.server.heartbeat();
+        assert!(health.ok(), "{:?}", health);
+        h.kill_notifier();
+        let health = h.server.heartbeat();
+        assert!(!health.ok());
+        assert!(health.initialized && health.runtime && health.updates_loop);
+        assert!(!health.notifier && health.resolver);
+    }
+
+    #[test]
+    fn resolver_down() {
+        let mut h = Harness::new();
+        h.server_start();
+        h.stop_resolver();
+        let deadline = Instant::now() + TIMEOUT;
+        while h.server.heartbeat().resolver {
+            assert!(Instant::now() < deadline, "resolver still looks reachable");
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        let health = h.server.heartbeat();
+        assert!(health.initialized && health.runtime && health.updates_loop);
+        assert!(!health.ok());
+    }
+
+    #[test]
+    fn wedged() {
+        let mut h = Harness::new();
+        h.server_start();
+        let conn = h.server.connection().unwrap();
+        let workers = std::thread::available_parallelism().unwrap().get();
+        for _ in 0..workers * 2 {
+            conn.runtime.spawn(async { std::thread::sleep(Duration::from_secs(2)) });
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        let health = h.server.heartbeat();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert!(health.initialized && !health.runtime && !health.ok());
+        let deadline = Instant::now() + TIMEOUT;
+        while !h.server.heartbeat().runtime {
+            assert!(Instant::now() < deadline, "runtime still wedged");
+        }
+    }
+}
+
+mod grid {
+    use super::*;
+    use crate::grid::Grid;
+
+    fn a(v: Vec<Value>) -> Value {
+        Value::from(v)
+    }
+
+    #[test]
+    fn shapes() {
+        let one = Value::I64(1);
+        let two = Value::I64(2);
+        let three = Value::I64(3);
+        assert_eq!(
+            Grid::of_value(&one),
+            Grid { rows: 1, columns: 1, cells: vec![one.clone()] }
+        );
+        assert_eq!(
+            Grid::of_value(&a(vec![])),
+            Grid { rows: 1, columns: 1, cells: vec![Value::Null] }
+        );
+        assert_eq!(
+            Grid::of_value(&a(vec![one.clone(), two.clone()])),
+            Grid { rows: 2, columns: 1, cells: vec![one.clone(), two.clone()] }
+        );
+        let v = a(vec![a(vec![one.clone(), two.clone()]), three.clone()]);
+        assert_eq!(
+            Grid::of_value(&v),
+            Grid {
+                rows: 2,
+                columns: 2,
+                cells: vec![one.clone(), two.clone(), three.clone(), Value::Null]
+            }
+        );
+        let deep = a(vec![a(vec![one.clone(), a(vec![two.clone()])])]);
+        assert_eq!(
+            Grid::of_value(&deep),
+            Grid { rows: 1, columns: 2, cells: vec![one, a(vec![two])] }
+        );
+    }
+
+    /// NetGetArray gets arrays through RTD as text, they must come
+    /// back out the same
+    #[test]
+    fn ranges() {
+        let v = |i| Value::I64(i);
+        let grid = |rows, columns, cells: Vec<Value>| Grid { rows, columns, cells };
+        assert_eq!(grid(1, 1, vec![v(1)]).into_value(), v(1));
+        assert_eq!(
+            grid(3, 1, vec![v(1), v(2), v(3)]).into_value(),
+            a(vec![v(1), v(2), v(3)])
+        );
+        assert_eq!(grid(1, 2, vec![v(1), v(2)]).into_value(), a(vec![v(1), v(2)]));
+        let square = a(vec![a(vec![v(1), v(2)]), a(vec![v(3), Value::Null])]);
+        let g = grid(2, 2, vec![v(1), v(2), v(3), Value::Null]);
+        assert_eq!(g.clone().into_value(), square);
+        assert_eq!(Grid::of_value(&square), g);
+        let column = grid(3, 1, vec![v(1), v(2), v(3)]);
+        assert_eq!(Grid::of_value(&column.clone().into_value()), column);
+    }
+
+    #[test]
+    fn typed_round_trip() {
+        let v = a(vec![
+            a(vec![Value::F64(0.1), Value::I64(-42), Value::from("a, \"b\"")]),
+            a(vec![Value::True, Value::Null, Value::U32(7)]),
+        ]);
+        let mut h = Harness::new();
+        h.publish("/test/foo", v.clone());
+        h.server_start();
+        h.connect_data_with(0, "/test/foo", &["format=typed"]);
+        h.wait_until(|sheet| match sheet.get(&TopicId(0)) {
+            Some(Cell::Update(Value::String(s))) => {
+                assert_eq!(s.parse::<Value>().unwrap(), v);
+                true
+            }
+            _ => false,
+        });
+    }
+}
+
+mod table {
+    use super::*;
+
+    fn table(rows: &[&[Value]]) -> Cell {
+        Cell::Update(Value::from(
+            rows.iter().map(|r| Value::from(r.to_vec())).collect::<Vec<_>>(),
+        ))
+    }
+
+    fn s(s: &'static str) -> Value {
+        Value::from(s)
+    }
+
+    #[test]
+    fn shape_changes() {
+        let mut h = Harness::new();
+        h.publish("/test/t/r1/a", Value::I64(1));
+        h.publish("/test/t/r1/b", Value::I64(2));
+        h.publish("/test/t/r0/a", Value::I64(3));
+        h.server_start();
+        h.connect_data_with(0, "/test/t", &["table=true"]);
+        h.wait_for(
+            0,
+            table(&[
+                &[s(""), s("a"), s("b")],
+                &[s("r0"), Value::I64(3), Value::Null],
+                &[s("r1"), Value::I64(1), Value::I64(2)],
+            ]),
+        );
+        h.publish("/test/t/r1/b", Value::I64(4));
+        h.publish("/test/t/r2/c", Value::I64(5));
+        h.wait_for(
+            0,
+            table(&[
+                &[s(""), s("a"), s("b"), s("c")],
+                &[s("r0"), Value::I64(3), Value::Null, Value::Null],
+                &[s("r1"), Value::I64(1), Value::I64(4), Value::Null],
+                &[s("r2"), Value::Null, Value::Null, Value::I64(5)],
+            ]),
+        );
+        h.unpublish("/test/t/r0/a");
+        h.unpublish("/test/t/r2/c");
+        h.wait_for(
+            0,
+            table(&[&[s(""), s("a"), s("b")], &[s("r1"), Value::I64(1), Value::I64(4)]]),
+        );
+        assert_eq!(h.server.counts(), (2, 1));
+        h.disconnect_data(0);
+        assert_eq!(h.server.counts(), (0, 0));
+    }
+
+    #[test]
+    fn selected() {
+        let mut h = Harness::new();
+        h.publish("/test/t/r1/a", Value::I64(1));
+        h.publish("/test/t/r1/b", Value::I64(2));
+        h.publish("/test/t/r0/a", Value::I64(3));
+        h.server_start();
+        h.connect_data_with(0, "/test/t", &["table=true", "columns=b,a", "rows=r1,r9"]);
+        h.wait_for(
+            0,
+            table(&[
+                &[s(""), s("b"), s("a")],
+                &[s("r1"), Value::I64(2), Value::I64(1)],
+                &[s("r9"), Value::Null, Value::Null],
+            ]),
+        );
+    }
+}
+
+mod list {
+    use super::*;
+
+    fn paths(paths: &[&'static str]) -> Cell {
+        Cell::Update(Value::from(
+            paths.iter().map(|p| Value::from(*p)).collect::<Vec<_>>(),
+        ))
+    }
+
+    #[test]
+    fn list() {
+        let mut h = Harness::new();
+        h.publish("/test/ns/a/x", Value::I64(1));
+        h.publish("/test/ns/a/y", Value::I64(2));
+        h.publish("/test/ns/b", Value::I64(3));
+        h.server_start();
+        h.connect_data_with(0, "/test/ns", &["list=true"]);
+        h.connect_data_with(1, "/test/ns", &["list=true", "recursive=true"]);
+        h.connect_data_with(2, "/test/ns", &["list=true", "glob=*/x"]);
+        h.connect_data_with(3, "/test/ns", &["list=true", "glob=/test/ns/?"]);
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&paths(&["/test/ns/a", "/test/ns/b"]))
+                && get(1) == Some(&paths(&["/test/ns/a/x", "/test/ns/a/y", "/test/ns/b"]))
+                && get(2) == Some(&paths(&["/test/ns/a/x"]))
+                && get(3) == Some(&paths(&["/test/ns/b"]))
+        });
+        h.publish("/test/ns/c", Value::I64(4));
+        h.unpublish("/test/ns/a/y");
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&paths(&["/test/ns/a", "/test/ns/b", "/test/ns/c"]))
+                && get(1) == Some(&paths(&["/test/ns/a/x", "/test/ns/b", "/test/ns/c"]))
+        });
+        h.disconnect_data(0);
+        assert_eq!(h.server.counts(), (0, 3));
+    }
+}
+
+mod serial {
+    use super::*;
+    use crate::serial;
+    use chrono::prelude::*;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, mi: u32, s: u32, ms: u32) -> DateTime<Utc> {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_milli_opt(h, mi, s, ms)
+            .unwrap()
+            .and_utc()
+    }
+
+    #[test]
+    fn known_dates() {
+        let cases = [
+            (utc(1900, 1, 1, 0, 0, 0, 0), 1.),
+            (utc(1900, 2, 28, 0, 0, 0, 0), 59.),
+            (utc(1900, 2, 28, 12, 0, 0, 0), 59.5),
+            (utc(1900, 3, 1, 0, 0, 0, 0), 61.),
+            (utc(2024, 1, 1, 0, 0, 0, 0), 45292.),
+            (utc(2024, 1, 1, 18, 0, 0, 0), 45292.75),
+        ];
+        for (d, v) in cases {
+            assert_eq!(serial::of_datetime(d, Tz::Utc), v);
+            assert_eq!(serial::to_datetime(v, Tz::Utc).unwrap(), d);
+        }
+        assert!(serial::to_datetime(-1., Tz::Utc).is_err());
+        assert!(serial::to_datetime(f64::NAN, Tz::Utc).is_err());
+    }
+
+    #[test]
+    fn round_trip() {
+        let dates = [
+            utc(1900, 1, 1, 0, 0, 0, 1),
+            utc(1900, 2, 28, 23, 59, 59, 999),
+            utc(1900, 3, 1, 0, 0, 0, 0),
+            utc(1999, 12, 31, 23, 59, 59, 0),
+            utc(2024, 7, 4, 9, 30, 15, 250),
+            utc(2100, 2, 28, 1, 2, 3, 4),
+        ];
+        for d in dates {
+            for tz in [Tz::Utc, Tz::Local] {
+                let v = serial::of_datetime(d, tz);
+                assert_eq!(serial::to_datetime(v, tz).unwrap(), d, "{tz:?} {v}");
+            }
+        }
+        for d in
+            [Duration::ZERO, Duration::from_secs(36 * 3600), Duration::from_millis(1)]
+        {
+            let v = serial::of_duration(d);
+            let back = serial::to_duration(v).unwrap();
+            assert!(back.abs_diff(d) < Duration::from_micros(1), "{d:?} {back:?}");
+        }
+        assert_eq!(serial::of_duration(Duration::from_secs(36 * 3600)), 1.5);
+    }
+
+    #[test]
+    fn out_of_range() {
+        for v in [1e8, 1e15, f64::MAX] {
+            assert!(serial::to_datetime(v, Tz::Utc).is_err(), "{v}");
+            assert!(serial::to_datetime(v, Tz::Local).is_err(), "{v}");
+        }
+        for v in [1e15, f64::MAX] {
+            assert!(serial::to_duration(v).is_err(), "{v}");
+        }
+        assert!(serial::to_duration(f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn through_server() {
+        let mut h = Harness::new();
+        let d = utc(2024, 1, 1, 6, 0, 0, 0);
+        h.publish("/test/serial/d", Value::DateTime(d));
+        h.publish("/test/serial/t", Value::Duration(Duration::from_secs(3 * 3600)));
+        h.server_start();
+        h.connect_data_with(0, "/test/serial/d", &["tz=utc"]);
+        h.connect_data_with(1, "/test/serial/t", &[]);
+        h.connect_data_with(2, "/test/serial/d", &["format=typed"]);
+        h.wait_until(|sheet| {
+            sheet.get(&TopicId(0)) == Some(&Cell::Update(Value::F64(45292.25)))
+                && sheet.get(&TopicId(1)) == Some(&Cell::Update(Value::F64(0.125)))
+                && sheet.get(&TopicId(2))
+                    == Some(&Cell::Update(Value::from(Value::DateTime(d).to_string())))
+        });
+    }
+}
+
+mod errors {
+    use super::*;
+    use crate::errors::classify;
+
+    fn rules() -> Vec<ErrorRule> {
+        vec![
+            ErrorRule { pattern: "Divide By Zero".into(), error: ExcelError::Div0 },
+            ErrorRule { pattern: "timed out".into(), error: ExcelError::NA },
+        ]
+    }
+
+    #[test]
+    fn classify_rules() {
+        let rules = rules();
+        assert_eq!(classify(&rules, "divide by zero in model"), ExcelError::Div0);
+        assert_eq!(classify(&rules, "timed out"), ExcelError::NA);
+        assert_eq!(classify(&rules, "#NUM! overflow"), ExcelError::Num);
+        assert_eq!(classify(&rules, "#ref! gone"), ExcelError::Ref);
+        assert_eq!(classify(&rules, "something else"), ExcelError::Value);
+        assert_eq!(classify(&[], "timed out"), ExcelError::Value);
+        let catch_all = [ErrorRule { pattern: "".into(), error: ExcelError::NA }];
+        assert_eq!(classify(&catch_all, "#NUM! overflow"), ExcelError::NA);
+        let rules: Vec<ErrorRule> = serde_json::from_str(
+            r##"[{ "pattern": "stale", "error": "#N/A" }, { "pattern": "x", "error": "#DIV/0!" }]"##,
+        )
+        .unwrap();
+        assert_eq!(rules[0].error, ExcelError::NA);
+        assert_eq!(rules[1].error, ExcelError::Div0);
+    }
+
+    #[test]
+    fn through_server() {
+        let mut h = Harness::new();
+        h.publish("/test/errors/a", Value::Error("divide by zero".into()));
+        h.publish("/test/errors/b", Value::I64(42));
+        h.server_start();
+        let options = TopicOptions { errors: rules().into(), ..Default::default() };
+        h.server.connect_data(TopicId(0), Path::from("/test/errors/a"), options).unwrap();
+        h.connect_data(1, "/test/errors/a");
+        h.connect_data_with(2, "/test/errors/a", &["errortext=true"]);
+        h.connect_data_with(3, "/test/errors/a", &["format=text"]);
+        h.connect_data_with(4, "/test/errors/b", &["errortext=true"]);
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Error(ExcelError::Div0))
+                && get(1) == Some(&Cell::Error(ExcelError::Value))
+                && get(2) == Some(&Cell::Update(Value::from("divide by zero")))
+                && matches!(get(3), Some(Cell::Update(Value::String(_))))
+                && get(4) == Some(&Cell::Update(Value::Null))
+        });
+        h.publish("/test/errors/a", Value::I64(1));
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Update(Value::I64(1)))
+                && get(2) == Some(&Cell::Update(Value::Null))
+        });
+    }
+}
+
+mod rendering {
+    use super::*;
+    use crate::options::{currency, BigInt, Bytes, Decimal};
+
+    fn dec(s: &str) -> rust_decimal::Decimal {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let t = TopicOptions::parse([] as [&str; 0]).unwrap();
+        assert_eq!(
+            (t.bytes, t.decimal, t.bigint),
+            (Bytes::Bin, Decimal::F64, BigInt::Number)
+        );
+        let t = TopicOptions::parse(["bytes=HEX", "decimal=currency", "bigint=string"])
+            .unwrap();
+        assert_eq!(
+            (t.bytes, t.decimal, t.bigint),
+            (Bytes::Hex, Decimal::Currency, BigInt::String)
+        );
+        assert!(TopicOptions::parse(["bytes=octal"]).is_err());
+        assert!(TopicOptions::parse(["decimal=float"]).is_err());
+        assert!(TopicOptions::parse(["bigint=huge"]).is_err());
+    }
+
+    #[test]
+    fn native() {
+        let b = Value::Bytes(vec![0u8, 1, 254, 255].into());
+        let t = TopicOptions::parse(["bytes=hex"]).unwrap();
+        assert_eq!(t.format(b.clone()), Value::from("0001feff"));
+        let t = TopicOptions::parse(["bytes=base64"]).unwrap();
+        assert_eq!(t.format(b.clone()), Value::from("AAH+/w=="));
+        let t = TopicOptions::default();
+        assert_eq!(t.format(b.clone()), b);
+        let d = Value::Decimal(dec("12345678901234567890.123"));
+        assert_eq!(t.format(Value::Decimal(dec("1.5"))), Value::F64(1.5));
+        let t = TopicOptions::parse(["decimal=string"]).unwrap();
+        assert_eq!(t.format(d.clone()), Value::from("12345678901234567890.123"));
+        let t = TopicOptions::parse(["decimal=decimal"]).unwrap();
+        assert_eq!(t.format(d.clone()), d);
+        let exact = 1i64 << 53;
+        let t = TopicOptions::default();
+        assert_eq!(t.format(Value::I64(exact + 1)), Value::I64(exact + 1));
+        let t = TopicOptions::parse(["bigint=string"]).unwrap();
+        assert_eq!(t.format(Value::I64(exact)), Value::I64(exact));
+        assert_eq!(t.format(Value::I64(-exact - 1)), Value::from("-9007199254740993"));
+        assert_eq!(t.format(Value::U64(u64::MAX)), Value::from("18446744073709551615"));
+        assert_eq!(t.format(Value::I32(42)), Value::I32(42));
+    }
+
+    #[test]
+    fn currencies() {
+        assert_eq!(currency(&dec("1.5")), Some(15_000));
+        assert_eq!(currency(&dec("-0.00006")), Some(-1));
+        assert_eq!(currency(&dec("123.45678")), Some(1_234_568));
+        assert_eq!(currency(&dec("12345678901234567890")), None);
+    }
+
+    #[test]
+    fn through_server() {
+        let mut h = Harness::new();
+        h.publish("/test/rendering/d", Value::Decimal(dec("2.25")));
+        h.publish("/test/rendering/big", Value::U64(u64::MAX));
+        h.server_start();
+        h.connect_data_with(0, "/test/rendering/d", &["decimal=currency"]);
+        h.connect_data_with(1, "/test/rendering/d", &["decimal=string"]);
+        h.connect_data_with(2, "/test/rendering/big", &["bigint=string"]);
+        h.connect_data_with(3, "/test/rendering/d", &["decimal=currency", "format=text"]);
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Currency(22_500))
+                && get(1) == Some(&Cell::Update(Value::from("2.25")))
+                && get(2) == Some(&Cell::Update(Value::from("18446744073709551615")))
+                && get(3) == Some(&Cell::Update(Value::from("2.25")))
+        });
+    }
+}
+
+mod set_type {
+    use super::*;
+    use crate::set_type::SetType;
+
+    fn set(typ: &str, v: Value) -> Result<Value, ExcelError> {
+        typ.parse::<SetType>().unwrap().apply(v, Tz::Utc)
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("".parse::<SetType>().unwrap(), SetType::Auto);
+        assert_eq!("U64".parse::<SetType>().unwrap(), SetType::U64);
+        assert_eq!("decimal".parse::<SetType>().unwrap(), SetType::Decimal);
+        assert!("u128".parse::<SetType>().is_err());
+    }
+
+    #[test]
+    fn integers() {
+        assert_eq!(set("i32", Value::F64(-42.)), Ok(Value::I32(-42)));
+        assert_eq!(set("u32", Value::F64(4294967295.)), Ok(Value::U32(u32::MAX)));
+        assert_eq!(set("u32", Value::F64(4294967296.)), Err(ExcelError::Num));
+        assert_eq!(set("u32", Value::F64(-1.)), Err(ExcelError::Num));
+        assert_eq!(set("z32", Value::I64(7)), Ok(Value::Z32(7)));
+        assert_eq!(set("v32", Value::True), Ok(Value::V32(1)));
+        assert_eq!(set("i64", Value::F64(1.5)), Err(ExcelError::Num));
+        assert_eq!(set("i64", Value::F64(f64::NAN)), Err(ExcelError::Num));
+        assert_eq!(set("i64", Value::F64(9.3e18)), Err(ExcelError::Num));
+        assert_eq!(set("i64", Value::F64(-9.3e18)), Err(ExcelError::Num));
+        assert_eq!(
+            set("u64", Value::F64(1.8e19)),
+            Ok(Value::U64(18_000_000_000_000_000_000))
+        );
+        assert_eq!(set("u64", Value::F64(1.9e19)), Err(ExcelError::Num));
+        assert_eq!(
+            set("u64", Value::from("18446744073709551615")),
+            Ok(Value::U64(u64::MAX))
+        );
+        assert_eq!(set("v64", Value::from("18446744073709551616")), Err(ExcelError::Num));
+        assert_eq!(
+            set("z64", Value::from(" -9007199254740993 ")),
+            Ok(Value::Z64(-9007199254740993))
+        );
+        assert_eq!(set("i64", Value::from("lots")), Err(ExcelError::Value));
+        assert_eq!(set("i64", Value::Null), Err(ExcelError::Value));
+        assert_eq!(set("i64", Value::Error("#N/A".into())), Err(ExcelError::Value));
+    }
+
+    #[test]
+    fn others() {
+        assert_eq!(set("f32", Value::F64(0.5)), Ok(Value::F32(0.5)));
+        assert_eq!(set("f32", Value::F64(1e39)), Err(ExcelError::Num));
+        assert_eq!(set("f64", Value::from("2.5")), Ok(Value::F64(2.5)));
+        assert_eq!(set("f64", Value::from("inf")), Err(ExcelError::Num));
+        assert_eq!(
+            set("decimal", Value::from("12345678901234567890.123")),
+            Ok(Value::Decimal("12345678901234567890.123".parse().unwrap()))
+        );
+        assert_eq!(
+            set("decimal", Value::F64(1.25)),
+            Ok(Value::Decimal("1.25".parse().unwrap()))
+        );
+        assert_eq!(set("decimal", Value::from("1e40")), Err(ExcelError::Num));
+        assert_eq!(set("decimal", Value::from("abc")), Err(ExcelError::Value));
+        assert_eq!(
+            set("duration", Value::F64(1.5)),
+            Ok(Value::Duration(Duration::from_secs(36 * 3600)))
+        );
+        assert_eq!(set("duration", Value::F64(-1.)), Err(ExcelError::Num));
+        assert_eq!(set("duration", Value::F64(1e15)), Err(ExcelError::Num));
+        assert_eq!(set("duration", Value::F64(f64::MAX)), Err(ExcelError::Num));
+        assert!(matches!(set("time", Value::F64(45292.)), Ok(Value::DateTime(_))));
+        assert_eq!(set("time", Value::F64(-1.)), Err(ExcelError::Num));
+        assert_eq!(set("time", Value::F64(1e8)), Err(ExcelError::Num));
+        assert_eq!(set("time", Value::F64(f64::MAX)), Err(ExcelError::Num));
+        assert_eq!(
+            set("bytes", Value::from("AAH+/w==")),
+            Ok(Value::Bytes(vec![0u8, 1, 254, 255].into()))
+        );
+        assert_eq!(set("bytes", Value::from("not base64!")), Err(ExcelError::Value));
+        assert_eq!(set("string", Value::F64(2.)), Ok(Value::from("2")));
+        assert_eq!(set("bool", Value::F64(2.)), Ok(Value::True));
+        assert_eq!(set("bool", Value::from("yes")), Err(ExcelError::Value));
+        assert_eq!(set("null", Value::from("x")), Ok(Value::Null));
+        let e = Value::Error("#N/A".into());
+        assert_eq!(set("auto", e.clone()), Ok(e));
+    }
+
+    #[test]
+    fn ranges() {
+        let a = |v: Vec<Value>| Value::from(v);
+        let range = a(vec![
+            a(vec![Value::F64(1.), Value::from("2")]),
+            a(vec![Value::F64(3.), Value::True]),
+        ]);
+        assert_eq!(
+            set("u32", range.clone()),
+            Ok(a(vec![
+                a(vec![Value::U32(1), Value::U32(2)]),
+                a(vec![Value::U32(3), Value::U32(1)])
+            ]))
+        );
+        assert_eq!(set("auto", range.clone()), Ok(range.clone()));
+        assert_eq!(set("null", range), Ok(Value::Null));
+        let bad = a(vec![Value::F64(1.), Value::F64(1e10)]);
+        assert_eq!(set("i32", bad), Err(ExcelError::Num));
+        let blank = a(vec![Value::F64(1.), Value::Null]);
+        assert_eq!(set("f64", blank), Err(ExcelError::Value));
+    }
+}
+
+mod write {
+    use super::*;
+
+    #[test]
+    fn replies() {
+        let mut h = Harness::new();
+        h.publish("/test/write/a", Value::I64(0));
+        let written = h.accept_writes("/test/write/a", |v| match v {
+            Value::I64(_) => Value::Ok,
+            _ => Value::Error("bad value".into()),
+        });
+        h.server_start();
+        h.connect_data_with(0, "/test/write/a", &["write=i64:42"]);
+        h.connect_data_with(1, "/test/write/a", &["write=\"x\""]);
+        h.connect_data_with(2, "/test/write/a", &["write=\"x\"", "errortext=true"]);
+        h.connect_data_with(3, "/test/write/nowhere", &["write=1", "timeout=0.2"]);
+        h.connect_data_with(
+            4,
+            "/test/write/nowhere",
+            &["write=1", "timeout=0.2", "errortext=true"],
+        );
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Update(Value::Ok))
+                && get(1) == Some(&Cell::Error(ExcelError::Value))
+                && get(2) == Some(&Cell::Update(Value::from("bad value")))
+                && get(3) == Some(&Cell::Error(ExcelError::Value))
+                && get(4)
+                    == Some(&Cell::Update(Value::from(
+                        "timed out waiting for the publisher",
+                    )))
+        });
+        let mut written = written.lock().clone();
+        written.sort_by_key(|v| v.to_string());
+        assert_eq!(written, vec![Value::from("x"), Value::from("x"), Value::I64(42)]);
+    }
+}
+
+mod setter {
+    use super::*;
+    use crate::{
+        comglue, connection,
+        setter::{batch, shared, Outcome, Setter, Subs},
+    };
+    use netidx::subscriber::Subscriber;
+
+    #[test]
+    fn eviction() {
+        let h = Harness::new();
+        let _rt = h.runtime.enter();
+        let subscriber = Subscriber::new(h.config.clone(), DesiredAuth::Anonymous)
+            .expect("subscriber");
+        let path = |s: &str| Path::from(String::from(s));
+        let (a, b, c) =
+            (path("/test/setter/a"), path("/test/setter/b"), path("/test/setter/c"));
+        let idle = Duration::from_secs(10);
+        let mut subs = Subs::new(idle, 2, false);
+        let t0 = Instant::now();
+        let id = subs.get(&subscriber, &a, t0).id();
+        subs.get(&subscriber, &b, t0 + Duration::from_secs(1));
+        // a was written last, so b is the least recently used
+        assert_eq!(subs.get(&subscriber, &a, t0 + Duration::from_secs(2)).id(), id);
+        subs.get(&subscriber, &c, t0 + Duration::from_secs(3));
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs.get(&subscriber, &a, t0 + Duration::from_secs(4)).id(), id);
+        subs.evict(t0 + Duration::from_millis(13_500));
+        assert_eq!(subs.len(), 1);
+        subs.evict(t0 + Duration::from_secs(15));
+        assert_eq!(subs.len(), 0);
+    }
+
+    #[test]
+    fn shared_with_server() {
+        let mut h = Harness::new();
+        h.publish("/test/setter/shared", Value::I64(1));
+        h.server_start();
+        h.connect_data(0, "/test/setter/shared");
+        h.wait_for(0, Cell::Update(Value::I64(1)));
+        let path = Path::from("/test/setter/shared");
+        let dv = shared(&path).expect("shared by the server");
+        let _rt = h.runtime.enter();
+        let subscriber = Subscriber::new(h.config.clone(), DesiredAuth::Anonymous)
+            .expect("subscriber");
+        let mut subs = Subs::new(Duration::from_secs(10), 10, false);
+        assert_eq!(subs.get(&subscriber, &path, Instant::now()).id(), dv.id());
+    }
+
+    #[test]
+    fn shares_the_server_connection() {
+        let mut h = Harness::new();
+        h.publish("/test/setter/conn", Value::I64(0));
+        let written = h.accept_writes("/test/setter/conn", |_| Value::Ok);
+        h.server_start();
+        let conn = h.server.connection().expect("connected");
+        let setter = Setter::new(&conn, false);
+        let path = Path::from("/test/setter/conn");
+        setter.set(path, Value::I64(42), Duration::ZERO).expect("set");
+        wait_written(&written, 1);
+        assert_eq!(&*written.lock(), &[Value::I64(42)]);
+    }
+
+    #[test]
+    fn batches() {
+        let s = |s: &'static str| Value::from(s);
+        let paths = Value::from(vec![s("/a"), Value::Null, s(" /b ")]);
+        let values = Value::from(vec![Value::F64(1.), Value::Null, s("2")]);
+        let b = batch(&paths, &values, &Value::Null, Tz::Utc).expect("batch");
+        assert_eq!(
+            b.writes,
+            vec![(Path::from("/a"), Value::F64(1.)), (Path::from("/b"), s("2"))]
+        );
+        assert_eq!(b.status, vec![s("#SET"), Value::Null, s("#SET")]);
+        // one type for all, or one for each
+        let b = batch(&paths, &values, &s("i64"), Tz::Utc).expect("batch");
+        let a = b.writes.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>();
+        assert_eq!(a, vec![Value::I64(1), Value::I64(2)]);
+        let types = Value::from(vec![s("u32"), Value::Null, s("string")]);
+        let b = batch(&paths, &values, &types, Tz::Utc).expect("batch");
+        let a = b.writes.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>();
+        assert_eq!(a, vec![Value::U32(1), s("2")]);
+        // nothing is written if any row fails
+        let values = Value::from(vec![Value::F64(1.5), Value::Null, s("2")]);
+        let b = batch(&paths, &values, &s("i64"), Tz::Utc).expect("batch");
+        assert!(b.writes.is_empty());
+        assert_eq!(
+            b.status,
+            vec![Value::Error("#NUM!".into()), Value::Null, s("#NOTSET")]
+        );
+        let b = batch(&paths, &values, &s("nonsense"), Tz::Utc).expect("batch");
+        assert_eq!(b.status[0], Value::Error("#N/A".into()));
+        let values = Value::from(vec![Value::F64(1.), s("x"), s("2")]);
+        let b = batch(&paths, &values, &Value::Null, Tz::Utc).expect("batch");
+        assert_eq!(b.status[1], Value::Error("#VALUE!".into()));
+        // the ranges must be the same size
+        let short = Value::from(vec![Value::F64(1.), Value::F64(2.)]);
+        assert_eq!(batch(&paths, &short, &Value::Null, Tz::Utc), Err(ExcelError::Value));
+        assert_eq!(batch(&paths, &values, &short, Tz::Utc), Err(ExcelError::Value));
+    }
+
+    #[test]
+    fn set_many() {
+        let mut h = Harness::new();
+        h.publish("/test/setter/many/a", Value::I64(0));
+        h.publish("/test/setter/many/b", Value::I64(0));
+        let a = h.accept_writes("/test/setter/many/a", |_| Value::Ok);
+        let b = h.accept_writes("/test/setter/many/b", |_| Value::Ok);
+        h.server_start();
+        let conn = h.server.connection().expect("connected");
+        let setter = Setter::new(&conn, false);
+        let writes = vec![
+            (Path::from("/test/setter/many/a"), Value::I64(1), Duration::ZERO),
+            (Path::from("/test/setter/many/b"), Value::I64(2), Duration::ZERO),
+        ];
+        let outcomes = setter.set_many(writes.clone()).expect("set");
+        assert_eq!(outcomes, vec![Outcome::Written, Outcome::Written]);
+        wait_written(&a, 1);
+        wait_written(&b, 1);
+        assert_eq!(&*a.lock(), &[Value::I64(1)]);
+        assert_eq!(&*b.lock(), &[Value::I64(2)]);
+        // the same values again aren't written
+        let outcomes = setter.set_many(writes).expect("set");
+        assert_eq!(outcomes, vec![Outcome::Skipped, Outcome::Skipped]);
+        // and the status of their rows says so
+        let s = |s: &'static str| Value::from(s);
+        let paths = Value::from(vec![s("/a"), Value::Null, s("/b"), s("/c")]);
+        let values = Value::from(vec![Value::F64(1.), Value::Null, s("2"), s("3")]);
+        let mut b = batch(&paths, &values, &Value::Null, Tz::Utc).expect("batch");
+        b.outcomes([Outcome::Skipped, Outcome::Written, Outcome::Pending]);
+        assert_eq!(b.status, vec![s("#SKIPPED"), Value::Null, s("#SET"), s("#PENDING")]);
+    }
+
+    /// wait for `n` values to be written
+    fn wait_written(written: &Mutex<Vec<Value>>, n: usize) {
+        let deadline = Instant::now() + TIMEOUT;
+        while written.lock().len() < n && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10))
+        }
+    }
+
+    #[test]
+    fn repeats() {
+        let mut h = Harness::new();
+        h.publish("/test/setter/repeats", Value::I64(0));
+        let written = h.accept_writes("/test/setter/repeats", |_| Value::Ok);
+        let _rt = h.runtime.enter();
+        let subscriber = Subscriber::new(h.config.clone(), DesiredAuth::Anonymous)
+            .expect("subscriber");
+        let path = Path::from("/test/setter/repeats");
+        let mut subs = Subs::new(Duration::from_secs(10), 10, false);
+        let t0 = Instant::now();
+        let outcomes = [1, 1, 2, 2, 1]
+            .into_iter()
+            .enumerate()
+            .map(|(i, v)| {
+                let now = t0 + Duration::from_millis(i as u64);
+                subs.write(&subscriber, &path, Value::I64(v), Duration::ZERO, now)
+            })
+            .collect::<Vec<_>>();
+        let (w, s) = (Outcome::Written, Outcome::Skipped);
+        assert_eq!(outcomes, [w, s, w, s, w]);
+        wait_written(&written, 3);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(&*written.lock(), &[Value::I64(1), Value::I64(2), Value::I64(1)]);
+        // unless repeats are asked for
+        let mut subs = Subs::new(Duration::from_secs(10), 10, true);
+        subs.write(&subscriber, &path, Value::I64(1), Duration::ZERO, t0);
+        subs.write(&subscriber, &path, Value::I64(1), Duration::ZERO, t0);
+        wait_written(&written, 5);
+        assert_eq!(written.lock().len(), 5);
+    }
+
+    #[test]
+    fn min_interval() {
+        let mut h = Harness::new();
+        h.publish("/test/setter/interval", Value::I64(0));
+        let written = h.accept_writes("/test/setter/interval", |_| Value::Ok);
+        let _rt = h.runtime.enter();
+        let subscriber = Subscriber::new(h.config.clone(), DesiredAuth::Anonymous)
+            .expect("subscriber");
+        let path = Path::from("/test/setter/interval");
+        let mut subs = Subs::new(Duration::from_secs(60), 10, false);
+        let interval = Duration::from_secs(10);
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+        subs.write(&subscriber, &path, Value::I64(1), interval, at(0));
+        assert_eq!(subs.next_due(), None);
+        let outcome = subs.write(&subscriber, &path, Value::I64(2), interval, at(1));
+        assert_eq!(outcome, Outcome::Pending);
+        subs.write(&subscriber, &path, Value::I64(3), interval, at(2));
+        assert_eq!(subs.next_due(), Some(at(10)));
+        subs.flush(at(5));
+        // held back values are kept through eviction
+        subs.evict(at(100));
+        assert_eq!(subs.len(), 1);
+        subs.flush(at(10));
+        assert_eq!(subs.next_due(), None);
+        // a held back value is dropped if the next one is the last written
+        subs.write(&subscriber, &path, Value::I64(4), interval, at(11));
+        subs.write(&subscriber, &path, Value::I64(3), interval, at(12));
+        assert_eq!(subs.next_due(), None);
+        wait_written(&written, 2);
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(&*written.lock(), &[Value::I64(1), Value::I64(3)]);
+    }
+
+    #[test]
+    fn eviction_keeps_pending() {
+        let mut h = Harness::new();
+        h.publish("/test/setter/pending", Value::I64(0));
+        let written = h.accept_writes("/test/setter/pending", |_| Value::Ok);
+        let _rt = h.runtime.enter();
+        let subscriber = Subscriber::new(h.config.clone(), DesiredAuth::Anonymous)
+            .expect("subscriber");
+        let path = Path::from("/test/setter/pending");
+        let other = |s: &str| Path::from(format!("/test/setter/other/{}", s));
+        let mut subs = Subs::new(Duration::from_secs(60), 1, false);
+        let interval = Duration::from_secs(10);
+        let t0 = Instant::now();
+        let at = |secs| t0 + Duration::from_secs(secs);
+        subs.write(&subscriber, &path, Value::I64(1), interval, at(0));
+        subs.write(&subscriber, &path, Value::I64(2), interval, at(1));
+        // the held back write keeps its subscription over the limit
+        subs.get(&subscriber, &other("a"), at(2));
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs.next_due(), Some(at(10)));
+        subs.flush(at(10));
+        wait_written(&written, 2);
+        assert_eq!(&*written.lock(), &[Value::I64(1), Value::I64(2)]);
+        // once it is written it is the least recently used again
+        subs.get(&subscriber, &other("b"), at(11));
+        assert_eq!(subs.len(), 2);
+        assert_eq!(subs.next_due(), None);
+    }
+
+    #[test]
+    fn netidx_config() {
+        let mut cfg = comglue::Profile {
+            resolvers: vec![
+                comglue::Resolver {
+                    addr: "127.0.0.1:4564".parse().expect("addr"),
+                    auth: comglue::ResolverAuth::Anonymous,
+                },
+                comglue::Resolver {
+                    addr: "127.0.0.2:4564".parse().expect("addr"),
+                    auth: comglue::ResolverAuth::Local(String::from("/tmp/netidx")),
+                },
+            ],
+            ..Default::default()
+        };
+        let config = connection::netidx_config(&cfg).expect("inline config");
+        assert_eq!(config.addrs.len(), 2);
+        assert!(matches!(connection::auth(&cfg, &config), DesiredAuth::Anonymous));
+        // the add-in's auth mechanism decides the default
+        cfg.auth_mechanism = Some(comglue::Auth::Kerberos);
+        let config = connection::netidx_config(&cfg).expect("inline config");
+        assert!(matches!(config.default_auth(), DesiredAuth::Krb5 { .. }));
+        // the resolvers take precedence over a config file
+        let path = std::env::temp_dir()
+            .join(format!("netidx-excel-client-{}.json", std::process::id()));
+        cfg.netidx_config = Some(path.clone());
+        assert_eq!(connection::netidx_config(&cfg).expect("inline").addrs.len(), 2);
+        cfg.resolvers.clear();
+        assert!(connection::netidx_config(&cfg).is_err());
+        let file = r#"{
+            "base": "/",
+            "addrs": [["127.0.0.3:4564", "Anonymous"]],
+            "default_auth": "Anonymous"
+        }"#;
+        std::fs::write(&path, file).expect("write");
+        let config = connection::netidx_config(&cfg);
+        std::fs::remove_file(&path).expect("remove");
+        let addrs = config.expect("config file").addrs;
+        assert_eq!(addrs[0].0, "127.0.0.3:4564".parse().expect("addr"));
+    }
+
+    #[test]
+    fn auth() {
+        let h = Harness::new();
+        let mut cfg = comglue::Profile::default();
+        assert!(matches!(connection::auth(&cfg, &h.config), DesiredAuth::Anonymous));
+        cfg.auth_mechanism = Some(comglue::Auth::Kerberos);
+        assert!(matches!(
+            connection::auth(&cfg, &h.config),
+            DesiredAuth::Krb5 { upn: None, spn: None }
+        ));
+        cfg.auth_mechanism = Some(comglue::Auth::Tls);
+        assert!(matches!(
+            connection::auth(&cfg, &h.config),
+            DesiredAuth::Tls { identity: None }
+        ));
+    }
+}
+
+mod rpc {
+    use super::*;
+    use crate::rpc::{args, call};
+    use netidx::subscriber::Subscriber;
+
+    #[test]
+    fn arguments() {
+        let s = |s: &str| Value::from(String::from(s));
+        let pairs = vec![
+            (s(" qty "), Value::F64(10.)),
+            (s("side"), s("buy")),
+            (s("note"), Value::Null),
+            (Value::Null, Value::Null),
+        ];
+        assert_eq!(
+            args(pairs),
+            Ok(vec![
+                (String::from("qty"), Value::F64(10.)),
+                (String::from("side"), s("buy")),
+                (String::from("note"), Value::Null),
+            ])
+        );
+        assert_eq!(args(vec![(Value::Null, Value::F64(1.))]), Err(ExcelError::Value));
+        assert_eq!(args(vec![(Value::F64(1.), Value::F64(1.))]), Err(ExcelError::Value));
+        assert_eq!(args(vec![(s(" "), Value::F64(1.))]), Err(ExcelError::Value));
+    }
+
+    #[test]
+    fn calls() {
+        let mut h = Harness::new();
+        let calls = Arc::new(Mutex::new(vec![]));
+        let log = calls.clone();
+        h.publish_proc("/test/rpc/add", &["a", "b"], move |args| {
+            log.lock().push(args.clone());
+            let sum = args.iter().map(|(_, v)| v.clone().cast_to::<f64>().unwrap_or(0.));
+            Value::F64(sum.sum())
+        });
+        let _rt = h.runtime.enter();
+        let subscriber = Subscriber::new(h.config.clone(), DesiredAuth::Anonymous)
+            .expect("subscriber");
+        let args = vec![
+            (String::from("a"), Value::F64(1.)),
+            (String::from("b"), Value::F64(2.)),
+        ];
+        let path = Path::from("/test/rpc/add");
+        let reply = h.runtime.block_on(call(&subscriber, path.clone(), args, TIMEOUT));
+        assert_eq!(reply, Value::F64(3.));
+        assert_eq!(
+            &*calls.lock(),
+            &[vec![
+                (String::from("a"), Value::F64(1.)),
+                (String::from("b"), Value::F64(2.))
+            ]]
+        );
+        // an argument the procedure doesn't take fails the call
+        let args = vec![(String::from("c"), Value::F64(1.))];
+        let reply = h.runtime.block_on(call(&subscriber, path, args, TIMEOUT));
+        assert!(matches!(reply, Value::Error(_)));
+        assert_eq!(calls.lock().len(), 1);
+        let path = Path::from("/test/rpc/missing");
+        let timeout = Duration::from_millis(500);
+        let reply = h.runtime.block_on(call(&subscriber, path, vec![], timeout));
+        assert!(matches!(reply, Value::Error(_)));
+    }
+}
+
+mod publish {
+    use super::*;
+    use crate::publish::{publish, release_unheld};
+
+    #[test]
+    fn publish_update_unpublish() {
+        let mut h = Harness::new();
+        h.server_start();
+        let conn = h.server.connection().expect("connection");
+        let path = Path::from("/test/publish/a");
+        publish(&conn, &path, Value::I64(42), false).expect("publish");
+        h.connect_data_with(0, "/test/publish/a", &["publish=true"]);
+        h.connect_data(1, "/test/publish/a");
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Update(Value::I64(42)))
+                && get(1) == Some(&Cell::Update(Value::I64(42)))
+        });
+        // a recalc updates it, without replacing the topic
+        publish(&conn, &path, Value::I64(43), false).expect("publish");
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Update(Value::I64(43)))
+                && get(1) == Some(&Cell::Update(Value::I64(43)))
+        });
+        // and it is unpublished when no topic holds it
+        h.disconnect_data(0);
+        h.wait_for(1, Cell::Unsubscribed);
+    }
+
+    #[test]
+    fn long_values() {
+        let mut h = Harness::new();
+        h.server_start();
+        let conn = h.server.connection().expect("connection");
+        let v = Value::from("x".repeat(1000));
+        publish(&conn, &Path::from("/test/publish/long"), v.clone(), false)
+            .expect("publish");
+        h.connect_data_with(0, "/test/publish/long", &["publish=true"]);
+        h.wait_for(0, Cell::Update(v));
+    }
+
+    #[test]
+    fn writes() {
+        let mut h = Harness::new();
+        h.server_start();
+        let conn = h.server.connection().expect("connection");
+        let w = Path::from("/test/publish/w");
+        publish(&conn, &w, Value::I64(1), true).expect("publish");
+        publish(&conn, &Path::from("/test/publish/r"), Value::I64(1), false)
+            .expect("publish");
+        h.connect_data_with(0, "/test/publish/w", &["publish=true"]);
+        h.connect_data_with(1, "/test/publish/r", &["publish=true"]);
+        h.wait_until(|sheet| sheet.len() == 2);
+        h.connect_data_with(2, "/test/publish/w", &["write=i64:7"]);
+        h.connect_data_with(3, "/test/publish/r", &["write=i64:7", "errortext=true"]);
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Update(Value::I64(7)))
+                && get(1) == Some(&Cell::Update(Value::I64(1)))
+                && get(2) == Some(&Cell::Update(Value::Ok))
+                && get(3) == Some(&Cell::Update(Value::from("read only")))
+        });
+        // a recalc with the same value in the sheet keeps the write
+        publish(&conn, &w, Value::I64(1), true).expect("publish");
+        publish(&conn, &w, Value::I64(2), true).expect("publish");
+        h.wait_for(0, Cell::Update(Value::I64(2)));
+    }
+
+    #[test]
+    fn unheld() {
+        let mut h = Harness::new();
+        h.server_start();
+        let conn = h.server.connection().expect("connection");
+        let (a, b) =
+            (Path::from("/test/publish/unheld"), Path::from("/test/publish/held"));
+        publish(&conn, &a, Value::I64(1), false).expect("publish");
+        publish(&conn, &b, Value::I64(2), false).expect("publish");
+        h.connect_data(0, "/test/publish/unheld");
+        h.connect_data_with(1, "/test/publish/held", &["publish=true"]);
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Update(Value::I64(1)))
+                && get(1) == Some(&Cell::Update(Value::I64(2)))
+        });
+        // as when the topic that was to hold it fails to connect
+        release_unheld(&a);
+        release_unheld(&b);
+        h.wait_for(0, Cell::Unsubscribed);
+        h.connect_data(2, "/test/publish/held");
+        h.wait_for(2, Cell::Update(Value::I64(2)));
+    }
+}
+
+mod publish_table {
+    use super::*;
+    use crate::publish_table::{publish, rows};
+
+    fn s(s: &'static str) -> Value {
+        Value::from(s)
+    }
+
+    fn range(rows: &[&[Value]]) -> Value {
+        Value::from(rows.iter().map(|r| Value::from(r.to_vec())).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn layout() {
+        let r = range(&[
+            &[s("id"), s("a"), Value::Null, s("b")],
+            &[s("x"), Value::I64(1), Value::I64(2), Value::I64(3)],
+            &[Value::Null, Value::I64(4), Value::I64(5), Value::I64(6)],
+            &[Value::F64(7.), Value::I64(8), Value::I64(9), Value::I64(10)],
+        ]);
+        let row = |name: &str, cells: &[(&str, Value)]| {
+            let cells = cells.iter().map(|(c, v)| (String::from(*c), v.clone()));
+            (String::from(name), cells.collect::<Vec<_>>())
+        };
+        assert_eq!(
+            rows(&r, None),
+            Ok(vec![
+                row("x", &[("a", Value::I64(1)), ("b", Value::I64(3))]),
+                row("7", &[("a", Value::I64(8)), ("b", Value::I64(10))]),
+            ])
+        );
+        assert_eq!(
+            rows(&r, Some("b")),
+            Ok(vec![
+                row("3", &[("id", s("x")), ("a", Value::I64(1))]),
+                row("6", &[("id", Value::Null), ("a", Value::I64(4))]),
+                row("10", &[("id", Value::F64(7.)), ("a", Value::I64(8))]),
+            ])
+        );
+        assert_eq!(rows(&r, Some("c")), Err(ExcelError::Value));
+        let dup = range(&[
+            &[s("id"), s("a")],
+            &[s("x"), Value::I64(1)],
+            &[s("x"), Value::I64(2)],
+        ]);
+        assert_eq!(rows(&dup, None), Err(ExcelError::Value));
+        let dup =
+            range(&[&[s("id"), s("a"), s("a")], &[s("x"), Value::I64(1), Value::I64(2)]]);
+        assert_eq!(rows(&dup, None), Err(ExcelError::Value));
+    }
+
+    #[test]
+    fn diffs() {
+        let mut h = Harness::new();
+        h.server_start();
+        let conn = h.server.connection().expect("connection");
+        let base = Path::from("/test/pt");
+        let r = range(&[
+            &[s(""), s("a"), s("b")],
+            &[s("r0"), Value::I64(1), Value::I64(2)],
+            &[s("r1"), Value::I64(3), Value::I64(4)],
+        ]);
+        publish(&conn, &base, rows(&r, None).expect("rows")).expect("publish");
+        h.connect_data_with(0, "/test/pt", &["publishtable=true"]);
+        h.connect_data(1, "/test/pt/r0/b");
+        h.connect_data(2, "/test/pt/r1/a");
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Update(Value::Ok))
+                && get(1) == Some(&Cell::Update(Value::I64(2)))
+                && get(2) == Some(&Cell::Update(Value::I64(3)))
+        });
+        // a recalc updates the cells that changed, and unpublishes the
+        // rows that are gone
+        let r =
+            range(&[&[s(""), s("a"), s("b")], &[s("r0"), Value::I64(1), Value::I64(5)]]);
+        publish(&conn, &base, rows(&r, None).expect("rows")).expect("publish");
+        h.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(1) == Some(&Cell::Update(Value::I64(5)))
+                && get(2) == Some(&Cell::Unsubscribed)
+        });
+        // and the whole table when the topic holding it disconnects
+        h.disconnect_data(0);
+        h.wait_for(1, Cell::Unsubscribed);
+    }
+
+    #[test]
+    fn failed_publish() {
+        let mut h = Harness::new();
+        h.server_start();
+        let conn = h.server.connection().expect("connection");
+        let base = Path::from("/test/ptf");
+        let r = range(&[&[s(""), s("a")], &[s("r0"), Value::I64(1)]]);
+        publish(&conn, &base, rows(&r, None).expect("rows")).expect("publish");
+        h.connect_data_with(0, "/test/ptf", &["publishtable=true"]);
+        h.connect_data(1, "/test/ptf/r0/a");
+        h.wait_for(1, Cell::Update(Value::I64(1)));
+        // a cell that is already published can't be published again
+        let taken = Path::from("/test/ptf/r1/a");
+        crate::publish::publish(&conn, &taken, Value::I64(0), false).expect("publish");
+        let r = range(&[
+            &[s(""), s("a")],
+            &[s("r0"), Value::I64(2)],
+            &[s("r1"), Value::I64(3)],
+        ]);
+        assert!(publish(&conn, &base, rows(&r, None).expect("rows")).is_err());
+        // so nothing is updated, and the next recalc updates every cell
+        crate::publish::release_unheld(&taken);
+        publish(&conn, &base, rows(&r, None).expect("rows")).expect("publish");
+        h.wait_for(1, Cell::Update(Value::I64(2)));
+        h.connect_data(2, "/test/ptf/r1/a");
+        h.wait_for(2, Cell::Update(Value::I64(3)));
+    }
+}
+
+mod reload {
+    use super::*;
+    use crate::comglue::watch;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn watches_the_file() {
+        let path = std::env::temp_dir()
+            .join(format!("netidx-excel-watch-{}.json", std::process::id()));
+        std::fs::write(&path, "{}").expect("write");
+        let changes = Arc::new(AtomicUsize::new(0));
+        let c = changes.clone();
+        watch(path.clone(), Duration::from_millis(10), move || {
+            c.fetch_add(1, Ordering::Relaxed);
+        });
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(changes.load(Ordering::Relaxed), 0);
+        std::fs::write(&path, r#"{ "log_level": "Debug" }"#).expect("write");
+        let deadline = Instant::now() + TIMEOUT;
+        while changes.load(Ordering::Relaxed) == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10))
+        }
+        assert_eq!(changes.load(Ordering::Relaxed), 1);
+        std::fs::remove_file(&path).expect("remove");
+    }
+}
+
+mod profiles {
+    use super::*;
+    use crate::{comglue, connection::split_profile};
+
+    #[test]
+    fn prefixes() {
+        let split = |p: &'static str| {
+            let p = Path::from(p);
+            let (profile, path) = split_profile(&p);
+            (profile.map(String::from), path)
+        };
+        assert_eq!(split("/foo/bar"), (None, Path::from("/foo/bar")));
+        assert_eq!(
+            split("uat:/foo/bar"),
+            (Some(String::from("uat")), Path::from("/foo/bar"))
+        );
+        assert_eq!(split("uat:/"), (Some(String::from("uat")), Path::from("/")));
+        // a colon further down is part of the path
+        assert_eq!(split("/foo/a:/b"), (None, Path::from("/foo/a:/b")));
+        assert_eq!(split(":/foo"), (None, Path::from(":/foo")));
+        let cfg: comglue::Config = serde_json::from_str(
+            r#"{
+                "log_level": "Off",
+                "resolvers": [{ "addr": "127.0.0.1:4564" }],
+                "profiles": { "uat": { "auth_mechanism": "Anonymous" } }
+            }"#,
+        )
+        .expect("config");
+        let default = cfg.profile(None).expect("default profile");
+        assert_eq!(default.resolvers.len(), 1);
+        let uat = cfg.profile(Some("uat")).expect("uat profile");
+        assert_eq!(uat.auth_mechanism, Some(comglue::Auth::Anonymous));
+        assert!(uat.resolvers.is_empty());
+        assert_eq!(cfg.profile(Some("prod")), None);
+    }
+
+    #[test]
+    fn routes_topics() {
+        let mut a = Harness::new();
+        let mut b = Harness::new();
+        a.server.add_profile("uat", b.config.clone(), DesiredAuth::Anonymous);
+        a.publish("/test/profile/x", Value::I64(1));
+        b.publish("/test/profile/x", Value::I64(2));
+        a.server_start();
+        b.server_start();
+        // the profile is b's netidx config, so publish with b's connection
+        let uat = b.server.connection().expect("connection");
+        let p = Path::from("uat:/test/profile/p");
+        crate::publish::publish(&uat, &p, Value::I64(5), false).expect("publish");
+        a.connect_data(0, "/test/profile/x");
+        a.connect_data(1, "uat:/test/profile/x");
+        a.connect_data_with(2, "uat:/test/profile/p", &["publish=true"]);
+        a.wait_until(|sheet| {
+            let get = |i| sheet.get(&TopicId(i));
+            get(0) == Some(&Cell::Update(Value::I64(1)))
+                && get(1) == Some(&Cell::Update(Value::I64(2)))
+                && get(2) == Some(&Cell::Update(Value::I64(5)))
+        });
+        // the publication is in the profile's namespace
+        b.connect_data(0, "/test/profile/p");
+        b.wait_for(0, Cell::Update(Value::I64(5)));
+        let unknown = Path::from("prod:/test/profile/x");
+        assert!(a
+            .server
+            .connect_data(TopicId(3), unknown, TopicOptions::default())
+            .is_err());
+        drop(a);
+    }
+}
@@ -113,6 +113,28 @@ pub(crate) fn excel12v(xlfn: Xlfn, px_res: &mut XLOPER12, opers: &[LPXLOPER12])
     }
 }
 
+/// The handle of a call to an asynchronous UDF, its `X` argument,
+/// used to return the result when it is ready
+pub(crate) struct AsyncHandle(XLOPER12);
+
+// the handle is an opaque token, and xlAsyncReturn may be called from
+// any thread
+unsafe impl Send for AsyncHandle {}
+
+impl AsyncHandle {
+    pub(crate) unsafe fn new(handle: LPXLOPER12) -> Self {
+        AsyncHandle(*handle)
+    }
+
+    /// Return `v` as the result of the call. `v` is copied by Excel.
+    pub(crate) fn ret(self, v: XLOper12) -> bool {
+        let mut res = XLOper12::empty();
+        let handle = &self.0 as *const XLOPER12 as LPXLOPER12;
+        excel12v(Xlfn::xlAsyncReturn, res.as_mut_xloper12(), &[handle, v.as_lpxloper12()])
+            == 0
+    }
+}
+
 fn excel_free(xloper: LPXLOPER12) -> i32 {
     match *PEXCEL12 {
         0 => xlcall::xlretFailed as i32,
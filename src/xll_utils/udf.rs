@@ -20,6 +20,28 @@ impl Udf {
         category: &str,
         help_text: &str,
         arg_help: &[&str],
+    ) -> anyhow::Result<()> {
+        self.register_as(1, arg_types, arg_text, category, help_text, arg_help)
+    }
+
+    /// Register a command, which can be run from the macro dialog or
+    /// bound to a button, rather than a worksheet function
+    pub fn register_command(
+        &self,
+        category: &str,
+        help_text: &str,
+    ) -> anyhow::Result<()> {
+        self.register_as(2, "J", "", category, help_text, &[])
+    }
+
+    fn register_as(
+        &self,
+        macro_type: i32,
+        arg_types: &str,
+        arg_text: &str,
+        category: &str,
+        help_text: &str,
+        arg_help: &[&str],
     ) -> anyhow::Result<()> {
         match super::excel12(Xlfn::xlGetName, &mut []) {
             Err(_) => anyhow::bail!("xlGetName failed"),
@@ -30,7 +52,7 @@ impl Udf {
                     XLOper12::from(arg_types),
                     XLOper12::from(self.name),
                     XLOper12::from(arg_text),
-                    XLOper12::from(1),
+                    XLOper12::from(macro_type),
                     XLOper12::from(category),
                     XLOper12::missing(),
                     XLOper12::missing(),
@@ -93,14 +93,6 @@ impl XLOper12 {
         self.0.xltype &= !xlbitDLLFree;
         self.0.xltype |= xlbitXLFree;
     }
-
-    // Construct an XLOper12 from an LPXLOPER12 without taking ownership. Intentionally NOT public
-    // because creating an XLOper12 without taking ownership has potential to be misused
-    const fn from_lpxloper12(xloper: LPXLOPER12) -> Self {
-        let mut result = Self(unsafe { *xloper });
-        result.0.xltype &= !(xlbitDLLFree | xlbitXLFree); // no ownership bits
-        result
-    }
 }
 
 impl Clone for XLOper12 {
@@ -197,6 +189,21 @@ impl From<std::string::FromUtf16Error> for ToStringError {
     }
 }
 
+/// The cells of an xltypeMulti in row major order
+unsafe fn cells(v: &xloper12) -> &[xloper12] {
+    let len = (v.val.array.rows * v.val.array.columns) as usize;
+    std::slice::from_raw_parts(v.val.array.lparray, len)
+}
+
+/// The only cell of a single cell xltypeMulti, a range of more than
+/// one cell isn't a scalar
+unsafe fn single(v: &xloper12) -> Option<&xloper12> {
+    match cells(v) {
+        [cell] => Some(cell),
+        _ => None,
+    }
+}
+
 impl TryFrom<&xloper12> for String {
     type Error = ToStringError;
 
@@ -212,9 +219,9 @@ impl TryFrom<&xloper12> for String {
         };
         String::from_utf16(bytes).map_err(ToStringError::from)
       }
-      | XLType::Multi => unsafe {
-        let p = v.val.array.lparray;
-        String::try_from(&XLOper12::from_lpxloper12(p.offset(0)))
+      | XLType::Multi => match unsafe { single(v) } {
+        Some(cell) => String::try_from(cell),
+        None => Err(ToStringError::InvalidType(XLType::Multi)),
       },
       | XLType::Bool => Ok(unsafe { v.val.xbool == 1 }.to_string()),
       | typ @ (
@@ -239,6 +246,25 @@ impl<'a> TryFrom<&'a XLOper12> for String {
     }
 }
 
+/// The strings in a single cell or a range, skipping empty cells
+impl TryFrom<&xloper12> for Vec<String> {
+    type Error = ToStringError;
+
+    fn try_from(v: &xloper12) -> Result<Vec<String>, Self::Error> {
+        match v.xltype() {
+            XLType::Missing | XLType::Nil => Ok(vec![]),
+            XLType::Multi => unsafe {
+                cells(v)
+                    .iter()
+                    .filter(|v| !matches!(v.xltype(), XLType::Missing | XLType::Nil))
+                    .map(String::try_from)
+                    .collect()
+            },
+            _ => Ok(vec![String::try_from(v)?]),
+        }
+    }
+}
+
 impl TryFrom<&xloper12> for f64 {
     type Error = ();
 
@@ -248,9 +274,9 @@ impl TryFrom<&xloper12> for f64 {
             XLType::Int => Ok(unsafe { v.val.w as f64 }),
             XLType::Str => Err(()),
             XLType::Bool => Ok((unsafe { v.val.xbool == 1 }) as i64 as f64),
-            XLType::Multi => unsafe {
-                let p = v.val.array.lparray;
-                f64::try_from(&*p.offset(0))
+            XLType::Multi => match unsafe { single(v) } {
+                Some(cell) => f64::try_from(cell),
+                None => Err(()),
             },
             XLType::Ref | XLType::SRef => Err(()), // TODO: Consider handling these cases
             XLType::Nil
@@ -280,9 +306,9 @@ impl TryFrom<&xloper12> for i64 {
             XLType::Int => Ok(unsafe { v.val.w.into() }),
             XLType::Str => Err(()),
             XLType::Bool => Ok((unsafe { v.val.xbool == 1 }) as i64),
-            XLType::Multi => unsafe {
-                let p = v.val.array.lparray;
-                i64::try_from(&*p.offset(0))
+            XLType::Multi => match unsafe { single(v) } {
+                Some(cell) => i64::try_from(cell),
+                None => Err(()),
             },
             XLType::Ref | XLType::SRef => Err(()), // TODO: Consider handling these cases
             XLType::Nil
@@ -312,9 +338,9 @@ impl TryFrom<&xloper12> for bool {
             XLType::Int => Ok(unsafe { v.val.w != 0 }),
             XLType::Str => Err(()),
             XLType::Bool => Ok(unsafe { v.val.xbool != 0 }),
-            XLType::Multi => unsafe {
-                let p = v.val.array.lparray;
-                bool::try_from(&*p.offset(0))
+            XLType::Multi => match unsafe { single(v) } {
+                Some(cell) => bool::try_from(cell),
+                None => Err(()),
             },
             XLType::Ref | XLType::SRef => Err(()), // TODO: Consider handling these cases
             XLType::Nil
@@ -365,10 +391,16 @@ impl From<&xloper12> for netidx::subscriber::Value {
                 xlerrGettingData => Value::Error("#GETTING_DATA".into()),
                 code => Value::Error(format!("#ERR{code}").into()),
             },
-            XLType::Multi => unsafe {
-                let p = v.val.array.lparray;
-                netidx::subscriber::Value::from(&*p.offset(0))
-            },
+            // a range is an array, see Grid::into_value
+            XLType::Multi => {
+                let cells = unsafe { cells(v) };
+                crate::grid::Grid {
+                    rows: unsafe { v.val.array.rows } as usize,
+                    columns: unsafe { v.val.array.columns } as usize,
+                    cells: cells.iter().map(Value::from).collect(),
+                }
+                .into_value()
+            }
             XLType::Ref => Value::Error(format!("#UNSUPPORTED_REF").into()),
             XLType::Flow => Value::Error(format!("#UNSUPPORTED_FLOW").into()),
             XLType::SRef => Value::Error(format!("#UNSUPPORTED_SREF").into()),
@@ -432,6 +464,64 @@ impl From<String> for XLOper12 {
     }
 }
 
+impl From<&netidx::subscriber::Value> for XLOper12 {
+    fn from(v: &netidx::subscriber::Value) -> XLOper12 {
+        use netidx::subscriber::Value;
+        match v {
+            Value::I32(_)
+            | Value::Z32(_)
+            | Value::U32(_)
+            | Value::V32(_)
+            | Value::I64(_)
+            | Value::Z64(_)
+            | Value::U64(_)
+            | Value::V64(_)
+            | Value::F32(_)
+            | Value::F64(_)
+            | Value::Decimal(_) => match v.clone().cast_to::<f64>() {
+                Ok(f) => f.into(),
+                Err(_) => XLOper12::error(XlErr::Num),
+            },
+            Value::True => true.into(),
+            Value::False => false.into(),
+            Value::String(s) => (&**s).into(),
+            Value::Bytes(_) => "#BIN".into(),
+            Value::Null => XLOper12::empty(),
+            Value::Ok => "OK".into(),
+            Value::Error(_) => XLOper12::error(XlErr::Value),
+            Value::DateTime(d) => d.to_string().into(),
+            Value::Duration(d) => format!("{}s", d.as_secs_f64()).into(),
+            Value::Array(_) => v.to_string().into(),
+        }
+    }
+}
+
+impl XLOper12 {
+    /// Construct an xltypeMulti from `cells` in row major order.
+    /// Returns xlerrValue if the array doesn't fit in a sheet.
+    pub fn multi(rows: usize, columns: usize, cells: Vec<XLOper12>) -> XLOper12 {
+        const MAX_ROWS: usize = 1_048_576;
+        const MAX_COLUMNS: usize = 16_384;
+        assert_eq!(rows * columns, cells.len());
+        if rows == 0 || columns == 0 || rows > MAX_ROWS || columns > MAX_COLUMNS {
+            return XLOper12::error(XlErr::Value);
+        }
+        let mut cells: Box<[XLOper12]> = cells.into_boxed_slice();
+        let lparray = cells.as_mut_ptr() as LPXLOPER12;
+        std::mem::forget(cells);
+        XLOper12(XLOPER12 {
+            xltype: xltypeMulti | xlbitDLLFree,
+            val: xloper12__bindgen_ty_1 {
+                array: xloper12__bindgen_ty_1__bindgen_ty_3 {
+                    lparray,
+                    rows: rows as RW,
+                    columns: columns as COL,
+                },
+            },
+        })
+    }
+}
+
 impl std::fmt::Display for XLOper12 {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self.xltype() {